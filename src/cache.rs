@@ -0,0 +1,573 @@
+use crate::cli::Config;
+use crate::digest::{read_digests, Algorithm, Digest, Digester};
+use crate::error::{Error, IOResultExt, KeepGoingExt, Result, Step};
+use crate::progress::PROGRESS;
+use crate::signal;
+use crate::stamp::{FileStamp, InodeKey};
+use fs2::FileExt;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// the magic number and the format version at the start of the cache file - the
+/// version must be increased on each change of the format of the entries
+const CACHE_HEADER: [u8; 8] = *b"HLDC\x01\x00\x00\x00";
+
+/// the cache saved in another format has already been reported
+static DROPPED_CACHE: AtomicBool = AtomicBool::new(false);
+
+/// the delay between two attempts to lock the cache
+const LOCK_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// a cached digest
+#[derive(Debug, Eq, PartialEq, Copy, Clone, Serialize, Deserialize)]
+pub struct CacheEntry {
+    pub digest: Digest,
+    /// the stamp of the file when its digest was computed
+    pub stamp: FileStamp,
+    /// the file is guaranteed to never change, so its stamp is not checked
+    pub stable: bool,
+    /// the last time the entry was used, in seconds since the epoch
+    pub last_used: u64,
+}
+
+impl CacheEntry {
+    /// is this entry usable for a file with this stamp
+    pub fn is_valid(&self, algorithm: Algorithm, stamp: &FileStamp) -> bool {
+        self.digest.algorithm() == algorithm && (self.stable || self.stamp == *stamp)
+    }
+
+    /// is this entry a reuse of the other one, too close to its last use to be
+    /// worth saving
+    fn is_recent_use_of(&self, other: &CacheEntry) -> bool {
+        let entry = CacheEntry {
+            last_used: other.last_used,
+            ..*self
+        };
+        entry == *other && self.last_used < other.last_used + LAST_USED_RESOLUTION
+    }
+}
+
+/// the precision of the last use of the entries, in seconds - it avoids saving
+/// the cache on every run just to refresh them
+const LAST_USED_RESOLUTION: u64 = 24 * 60 * 60;
+
+/// the current time, in seconds since the epoch
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+/// the cache statistics of the run
+struct CacheStats {
+    hits: AtomicUsize,
+    misses: AtomicUsize,
+    evictions: AtomicUsize,
+    stale: AtomicUsize,
+}
+
+static STATS: CacheStats = CacheStats {
+    hits: AtomicUsize::new(0),
+    misses: AtomicUsize::new(0),
+    evictions: AtomicUsize::new(0),
+    stale: AtomicUsize::new(0),
+};
+
+/// log the cache statistics of the run
+pub fn log_stats() {
+    debug!(
+        "cache: {} hits, {} misses, {} evictions, {} stale entries",
+        STATS.hits.load(Ordering::Relaxed),
+        STATS.misses.load(Ordering::Relaxed),
+        STATS.evictions.load(Ordering::Relaxed),
+        STATS.stale.load(Ordering::Relaxed)
+    );
+}
+
+pub type Cache = HashMap<PathBuf, CacheEntry>;
+
+/// the cached digests usable in a run, by path and by inode
+///
+/// The inode index lets the renamed files, and the other links to a cached
+/// inode, reuse the digest of a file whose content has not changed.
+#[derive(Debug, Default)]
+pub struct CachedDigests {
+    algorithm: Option<Algorithm>,
+    paths: Cache,
+    inodes: HashMap<InodeKey, Digest>,
+}
+
+impl CachedDigests {
+    fn new(algorithm: Algorithm) -> CachedDigests {
+        CachedDigests {
+            algorithm: Some(algorithm),
+            ..Default::default()
+        }
+    }
+
+    fn insert(&mut self, path: PathBuf, entry: CacheEntry) {
+        self.index(&entry);
+        self.paths.insert(path, entry);
+    }
+
+    /// only index the entry, for the paths that are not there anymore
+    fn index(&mut self, entry: &CacheEntry) {
+        if Some(entry.digest.algorithm()) == self.algorithm {
+            self.inodes.insert(entry.stamp.inode_key(), entry.digest);
+        }
+    }
+
+    /// the digest of a file matched by a cache glob, already counted in the
+    /// statistics when the cache was updated
+    pub fn get_stable(&self, path: &Path) -> Option<Digest> {
+        self.paths
+            .get(path)
+            .filter(|entry| entry.stable)
+            .map(|entry| entry.digest)
+    }
+
+    /// the cached digest of a file, if any
+    ///
+    /// The inode index is only used for the paths without any entry: it doesn't
+    /// compare the change time, so it can't tell whether a file has been
+    /// modified in place since its entry was saved.
+    pub fn get(&self, path: &Path, stamp: &FileStamp) -> Option<Digest> {
+        let digest = match self.paths.get(path) {
+            Some(entry) => self
+                .algorithm
+                .is_some_and(|a| entry.is_valid(a, stamp))
+                .then_some(entry.digest),
+            None => {
+                let digest = self.inodes.get(&stamp.inode_key()).copied();
+                if digest.is_some() {
+                    debug!("using the cached digest of the inode of {}", path.display());
+                }
+                digest
+            }
+        };
+        let counter = if digest.is_some() {
+            &STATS.hits
+        } else {
+            &STATS.misses
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        digest
+    }
+}
+
+/// lock the cache, until the returned file is dropped
+///
+/// The readers share the lock, and the writers only hold it exclusively the
+/// time to merge their changes. Returns `None` when the cache is locked by
+/// another process and `--no-wait` is used.
+fn lock(config: &Config, exclusive: bool) -> Result<Option<File>> {
+    let cache_dir = config.cache_path.parent().unwrap().to_owned();
+    fs::create_dir_all(&cache_dir).path_ctx(&cache_dir)?;
+    let lock_path = config.cache_path.with_extension("lock");
+    let lock_file = File::create(&lock_path).path_ctx(&lock_path)?;
+    // a timeout too large to be represented never expires
+    let deadline = config
+        .lock_timeout
+        .and_then(|timeout| Instant::now().checked_add(Duration::from_secs(timeout)));
+    let mut waiting = false;
+    loop {
+        let res = if exclusive {
+            FileExt::try_lock_exclusive(&lock_file)
+        } else {
+            FileExt::try_lock_shared(&lock_file)
+        };
+        match res {
+            Ok(()) => return Ok(Some(lock_file)),
+            Err(e) if e.raw_os_error() == fs2::lock_contended_error().raw_os_error() => (),
+            Err(e) => return Err(e).path_ctx(&lock_path),
+        }
+        if config.no_wait {
+            warn!(
+                "{}: the cache is locked {} - skipping it",
+                lock_path.display(),
+                lock_holder(&lock_file)
+            );
+            return Ok(None);
+        }
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            return Err(Error::LockTimeout {
+                holder: lock_holder(&lock_file),
+                path: lock_path,
+            });
+        }
+        if !waiting {
+            debug!(
+                "waiting for the cache lock held {}",
+                lock_holder(&lock_file)
+            );
+            waiting = true;
+        }
+        // check regularly for an interruption, rather than blocking in the lock
+        signal::check()?;
+        thread::sleep(LOCK_RETRY_DELAY);
+    }
+}
+
+/// describe the processes holding the lock of the cache
+fn lock_holder(lock_file: &File) -> String {
+    let pids = lock_pids(lock_file);
+    if pids.is_empty() {
+        return "by an unknown process - maybe on another host, or a stale lock".to_owned();
+    }
+    pids.iter()
+        .map(|pid| {
+            if Path::new(&format!("/proc/{pid}")).exists() {
+                format!("by the process {pid}")
+            } else {
+                format!("by the process {pid}, which is not running anymore - a stale lock")
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// the processes holding a lock on the file, as listed in /proc/locks
+#[cfg(target_os = "linux")]
+fn lock_pids(lock_file: &File) -> Vec<u32> {
+    use std::os::unix::fs::MetadataExt;
+
+    let (Ok(metadata), Ok(locks)) = (lock_file.metadata(), fs::read_to_string("/proc/locks"))
+    else {
+        return vec![];
+    };
+    let dev = metadata.dev();
+    let id = format!(
+        "{:02x}:{:02x}:{}",
+        libc::major(dev),
+        libc::minor(dev),
+        metadata.ino()
+    );
+    let mut pids: Vec<u32> = locks
+        .lines()
+        // the "->" lines are the processes waiting for the lock
+        .filter(|line| !line.contains("->"))
+        .filter_map(|line| {
+            let fields: Vec<_> = line.split_whitespace().collect();
+            if fields.get(5) != Some(&id.as_str()) {
+                return None;
+            }
+            fields.get(4)?.parse().ok()
+        })
+        .collect();
+    pids.sort();
+    pids.dedup();
+    pids
+}
+
+#[cfg(not(target_os = "linux"))]
+fn lock_pids(_lock_file: &File) -> Vec<u32> {
+    vec![]
+}
+
+/// read the cache under a shared lock
+fn read(config: &Config) -> Result<Cache> {
+    let Some(lock_file) = lock(config, false)? else {
+        return Ok(hashmap! {});
+    };
+    let cache = read_unlocked(config)?;
+    FileExt::unlock(&lock_file).path_ctx(&config.cache_path)?;
+    Ok(cache)
+}
+
+fn read_unlocked(config: &Config) -> Result<Cache> {
+    let reader = match File::open(&config.cache_path) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(hashmap! {}),
+        res => res.path_ctx(&config.cache_path)?,
+    };
+    debug!("reading cache");
+    let mut reader = io::BufReader::new(reader);
+    let mut header = [0; CACHE_HEADER.len()];
+    match reader.read_exact(&mut header) {
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => (),
+        res => res.path_ctx(&config.cache_path)?,
+    }
+    if header != CACHE_HEADER {
+        // the cache saved by another version is only a loss of time - start a new one
+        if !DROPPED_CACHE.swap(true, Ordering::Relaxed) {
+            warn!(
+                "{}: dropping the cache saved in another format",
+                config.cache_path.display()
+            );
+        }
+        return Ok(hashmap! {});
+    }
+    bincode::deserialize_from(reader).map_err(|source| Error::CorruptedCache {
+        source,
+        path: config.cache_path.clone(),
+    })
+}
+
+/// write the cache in a temporary file renamed over the previous one, so the
+/// readers never see a partially written cache
+fn write(config: &Config, cache: &Cache) -> Result<()> {
+    debug!("saving updated cache with {} entries", cache.len());
+    if !config.dry_run {
+        let tmp_path = config
+            .cache_path
+            .with_extension(format!("tmp.{}", std::process::id()));
+        let output_file = File::create(&tmp_path).path_ctx(&tmp_path)?;
+        let mut writer = io::BufWriter::new(&output_file);
+        writer.write_all(&CACHE_HEADER).path_ctx(&tmp_path)?;
+        bincode::serialize_into(&mut writer, cache)?;
+        writer.flush().path_ctx(&tmp_path)?;
+        drop(writer);
+        fs::rename(&tmp_path, &config.cache_path).path_ctx(&config.cache_path)?;
+    }
+    Ok(())
+}
+
+/// the changes made to the cache by this process
+#[derive(Debug, Default)]
+struct CacheChanges {
+    /// the previous entries are discarded
+    cleared: bool,
+    inserted: Cache,
+    /// the entries to remove, if they were not updated in the meantime
+    removed: Cache,
+}
+
+impl CacheChanges {
+    fn is_empty(&self) -> bool {
+        !self.cleared && self.inserted.is_empty() && self.removed.is_empty()
+    }
+}
+
+/// merge the changes in the cache, along with the ones saved by the concurrent
+/// processes since it was read
+fn merge(config: &Config, changes: CacheChanges) -> Result<()> {
+    let limited = config.cache_max_entries.is_some() || config.cache_max_bytes.is_some();
+    if changes.is_empty() && !limited {
+        return Ok(());
+    }
+    let Some(lock_file) = lock(config, true)? else {
+        return Ok(());
+    };
+    let mut cache = if changes.cleared {
+        hashmap! {}
+    } else {
+        read_unlocked(config)?
+    };
+    let mut updated = changes.cleared;
+    for (path, entry) in changes.removed {
+        if cache.get(&path) == Some(&entry) {
+            cache.remove(&path);
+            updated = true;
+        }
+    }
+    for (path, entry) in changes.inserted {
+        // the cached files are guaranteed to never change - keep them that way
+        let previous = cache.get(&path);
+        if previous.is_some_and(|e| entry.is_recent_use_of(e)) {
+            continue;
+        }
+        if entry.stable || previous.is_none_or(|e| !e.stable) {
+            cache.insert(path, entry);
+            updated = true;
+        }
+    }
+    updated |= evict(config, &mut cache)?;
+    if updated {
+        write(config, &cache)?;
+    }
+    FileExt::unlock(&lock_file).path_ctx(&config.cache_path)?;
+    Ok(())
+}
+
+/// evict the least recently used entries exceeding the cache limits
+fn evict(config: &Config, cache: &mut Cache) -> Result<bool> {
+    let max_entries = config.cache_max_entries.unwrap_or(usize::MAX);
+    let max_bytes = config.cache_max_bytes.unwrap_or(u64::MAX);
+    let mut entries = cache
+        .iter()
+        .map(|(path, entry)| {
+            Ok((
+                entry.last_used,
+                bincode::serialized_size(&(path, entry))?,
+                path,
+            ))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let mut bytes: u64 = entries.iter().map(|(_, size, _)| size).sum();
+    if entries.len() <= max_entries && bytes <= max_bytes {
+        return Ok(false);
+    }
+    entries.sort();
+    let mut evicted = vec![];
+    for (_, size, path) in entries.iter() {
+        if cache.len() - evicted.len() <= max_entries && bytes <= max_bytes {
+            break;
+        }
+        bytes -= size;
+        evicted.push((*path).clone());
+    }
+    debug!("evicting {} cache entries", evicted.len());
+    STATS.evictions.fetch_add(evicted.len(), Ordering::Relaxed);
+    for path in evicted {
+        cache.remove(&path);
+    }
+    Ok(true)
+}
+
+/// update the cache with the digests of the cached files, and return them
+///
+/// With `--cache-all`, the still valid entries of the other files are also
+/// returned.
+pub fn update_cache(
+    config: &Config,
+    digester: &Digester,
+    paths: &[PathBuf],
+) -> Result<CachedDigests> {
+    let mut cache = if config.clear_cache {
+        hashmap! {}
+    } else {
+        read(config)?
+    };
+    let mut changes = CacheChanges {
+        cleared: config.clear_cache,
+        ..Default::default()
+    };
+    let last_used = now();
+
+    // seed the cache with the imported digests of the existing files
+    for path in &config.import_digests {
+        for (file, digest) in read_digests(digester.algorithm(), path)? {
+            let Ok(metadata) = fs::metadata(&file) else {
+                continue;
+            };
+            let entry = CacheEntry {
+                digest,
+                stamp: FileStamp::from(&metadata),
+                stable: true,
+                last_used,
+            };
+            if cache.get(&file).map(|e| (e.digest, e.stable)) != Some((digest, true)) {
+                changes.inserted.insert(file.clone(), entry);
+                cache.insert(file, entry);
+            }
+        }
+    }
+
+    // remove the dead entries, and the stale ones
+    let (live_cache, dead_cache): (Cache, Cache) = cache
+        .into_iter()
+        .collect::<Vec<(_, _)>>()
+        .into_par_iter()
+        .partition(|(path, entry)| {
+            let metadata = fs::metadata(path);
+            if entry.stable {
+                metadata.is_ok()
+            } else {
+                metadata.is_ok_and(|m| FileStamp::from(&m) == entry.stamp)
+            }
+        });
+
+    // the inodes of the dead entries may still be there, under another path
+    let mut cached = CachedDigests::new(digester.algorithm());
+    STATS.stale.fetch_add(dead_cache.len(), Ordering::Relaxed);
+    for (path, entry) in dead_cache {
+        if fs::metadata(&path).is_ok() {
+            // the file has changed, so its inode isn't trusted anymore - the entry
+            // is kept to prevent the use of the inode index for this path
+            cached.paths.insert(path.clone(), entry);
+        } else if entry.stable || config.cache_all {
+            cached.index(&entry);
+        }
+        changes.inserted.remove(&path);
+        changes.removed.insert(path, entry);
+    }
+    // the stable files are only trusted by path when matched by a cache glob
+    for (path, entry) in &live_cache {
+        if !entry.stable && config.cache_all {
+            cached.insert(path.clone(), *entry);
+        } else if entry.stable {
+            cached.index(entry);
+        }
+    }
+
+    // compute the digest for the entries not already there, or computed with
+    // another algorithm
+    let new_digests = paths
+        .par_iter()
+        .filter_map(|path| {
+            let entry = (|| {
+                signal::check()?;
+                let valid = live_cache.get(path).filter(|entry| {
+                    entry.stable && entry.digest.algorithm() == digester.algorithm()
+                });
+                if let Some(entry) = valid {
+                    STATS.hits.fetch_add(1, Ordering::Relaxed);
+                    let entry = CacheEntry {
+                        last_used,
+                        ..*entry
+                    };
+                    return Ok(entry);
+                }
+                let stamp = FileStamp::from(&fs::metadata(path).path_ctx(path)?);
+                let digest = match cached.get(path, &stamp) {
+                    Some(digest) => digest,
+                    None => {
+                        PROGRESS.to_hash(stamp.size);
+                        let digest = digester.file_digest(path)?;
+                        PROGRESS.hashed(stamp.size);
+                        digest
+                    }
+                };
+                Ok(CacheEntry {
+                    digest,
+                    stamp,
+                    stable: true,
+                    last_used,
+                })
+            })();
+            entry
+                .keep_going(config.keep_going, Step::Hashing)
+                .map(|entry| entry.map(|entry| (path.clone(), entry)))
+                .transpose()
+        })
+        .collect::<Result<Cache>>()?;
+    changes.inserted.extend(new_digests.clone());
+    for (path, entry) in new_digests {
+        cached.insert(path, entry);
+    }
+
+    merge(config, changes)?;
+    Ok(cached)
+}
+
+/// save the digests computed in this run in the cache, along with the stamp of
+/// their file
+pub fn save_computed_digests(config: &Config, digester: &Digester) -> Result<()> {
+    let last_used = now();
+    let inserted = digester
+        .computed_digests()
+        .into_iter()
+        .map(|(path, (stamp, digest))| {
+            let entry = CacheEntry {
+                digest,
+                stamp,
+                stable: false,
+                last_used,
+            };
+            (path, entry)
+        })
+        .collect();
+    let changes = CacheChanges {
+        inserted,
+        ..Default::default()
+    };
+    merge(config, changes)
+}
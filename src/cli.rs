@@ -1,8 +1,11 @@
+use crate::digest::{Algorithm, HashIo};
 use crate::strategy::*;
 use clap::{Parser, ValueEnum};
 use clap_complete::Shell;
 use directories::ProjectDirs;
+use std::num::NonZeroUsize;
 use std::path::PathBuf;
+use std::time::Duration;
 use strum::Display;
 
 /// Hard Link Deduplicator
@@ -17,30 +20,140 @@ pub struct Config {
     #[arg(short, long = "cache")]
     pub caches: Vec<String>,
 
+    /// Files to exclude, matched against their whole path
+    #[arg(long = "exclude")]
+    pub excludes: Vec<String>,
+
+    /// Configuration file, defining some named profiles
+    #[arg(long, value_name = "FILE", env = "HLD_CONFIG")]
+    pub config: Option<PathBuf>,
+
+    /// The profile of the configuration file to use - "default" if it exists
+    #[arg(short = 'P', long, env = "HLD_PROFILE")]
+    pub profile: Option<String>,
+
     /// Cache file
     #[arg(short = 'C', long, default_value = defaut_cache_path().into_os_string(), env = "HLD_CACHE_PATH")]
     pub cache_path: PathBuf,
 
+    /// Cache the digests of all the files, validated by their inode and modification time
+    #[arg(long, env = "HLD_CACHE_ALL")]
+    pub cache_all: bool,
+
+    /// Maximum number of entries in the cache, the least recently used ones being evicted
+    #[arg(long, value_name = "N", env = "HLD_CACHE_MAX_ENTRIES")]
+    pub cache_max_entries: Option<usize>,
+
+    /// Maximum size of the cache in bytes, the least recently used entries being evicted
+    #[arg(long, value_name = "BYTES", env = "HLD_CACHE_MAX_BYTES")]
+    pub cache_max_bytes: Option<u64>,
+
+    /// Fail when the cache is still locked by another process after this number of seconds
+    #[arg(long, value_name = "SECS", env = "HLD_LOCK_TIMEOUT")]
+    pub lock_timeout: Option<u64>,
+
+    /// Don't use the cache when it is locked by another process, instead of waiting for it
+    #[arg(long, env = "HLD_NO_WAIT")]
+    pub no_wait: bool,
+
     /// Clear the cache file
     #[arg(long)]
     pub clear_cache: bool,
 
+    /// Seed the cache with the digests listed in a file in the sha256sum format
+    #[arg(long = "import-digests", value_name = "FILE")]
+    pub import_digests: Vec<PathBuf>,
+
+    /// Print the digests of the files in the sha256sum format instead of deduplicating them
+    #[arg(long)]
+    pub print_digests: bool,
+
+    /// Resume an interrupted run, reusing the digests saved in its checkpoint
+    #[arg(long)]
+    pub resume: bool,
+
     /// Recursively find the files in the provided paths
     #[arg(short, long, env = "HLD_RECURSIVE")]
     pub recursive: bool,
 
+    /// Follow the symbolic links and process the files they point to
+    #[arg(short = 'L', long, env = "HLD_FOLLOW_SYMLINKS")]
+    pub follow_symlinks: bool,
+
+    /// Replace the symbolic links with the same target by hardlinks of a single symbolic link
+    #[arg(long, conflicts_with = "follow_symlinks")]
+    pub dedup_symlinks: bool,
+
+    /// Also share the identical blocks of the files larger than this size in bytes, with the kernel deduplication
+    #[arg(long, value_name = "MIN_SIZE", env = "HLD_PARTIAL")]
+    pub partial: Option<u64>,
+
+    /// Skip the files modified more recently than this duration - for example 30s, 10m, 2h or 1d
+    #[arg(long, value_parser = parse_duration, env = "HLD_MIN_AGE")]
+    pub min_age: Option<Duration>,
+
+    /// Skip the files with an error instead of aborting the run, and report them at the end
+    #[arg(short, long, env = "HLD_KEEP_GOING")]
+    pub keep_going: bool,
+
+    /// Exit with the code 5 when there is nothing to deduplicate
+    #[arg(long, env = "HLD_DETAILED_EXIT_CODES")]
+    pub detailed_exit_codes: bool,
+
     /// Don't modify anything on the disk
     #[arg(short = 'n', long)]
     pub dry_run: bool,
 
-    /// The linking strategy to use - either hardlink, symlink or reflink
-    #[arg(short, long, default_value_t = Strategy::HardLink, env = "HLD_STRATEGY")]
-    pub strategy: Strategy,
+    /// The linking strategies to try in order for each file - hardlink, symlink, reflink or dedupe
+    #[arg(
+        short,
+        long,
+        value_delimiter = ',',
+        default_value = "hardlink",
+        env = "HLD_STRATEGY"
+    )]
+    pub strategy: Vec<Strategy>,
+
+    /// The digest algorithm - either blake3, sha1, sha256 or xxh3
+    #[arg(short, long, default_value_t = Algorithm::Blake3, env = "HLD_DIGEST")]
+    pub digest: Algorithm,
+
+    /// Use the digests found in the sidecar files, like foo.jar.sha1 for foo.jar with --digest sha1
+    #[arg(long, env = "HLD_DIGEST_SIDECAR")]
+    pub digest_sidecar: bool,
+
+    /// Use the digests found in a manifest file in the sha256sum format
+    #[arg(long = "digest-manifest", value_name = "FILE")]
+    pub digest_manifests: Vec<PathBuf>,
+
+    /// Compare the content of the files byte by byte before linking them
+    #[arg(long, env = "HLD_VERIFY")]
+    pub verify: bool,
+
+    /// The way the files are read to compute their digest - either auto, mmap or buffered
+    #[arg(long, default_value_t = HashIo::Auto, env = "HLD_HASH_IO")]
+    pub hash_io: HashIo,
 
     /// Parallelism level
     #[arg(short = 'j', long, env = "HLD_PARALLEL")]
     pub parallel: Option<usize>,
 
+    /// Number of threads reading the files - defaults to the parallelism level
+    #[arg(long, value_name = "N", env = "HLD_IO_THREADS")]
+    pub io_threads: Option<usize>,
+
+    /// Number of threads hashing the memory mapped files, with --hash-io mmap - defaults to the number of I/O threads
+    #[arg(long, value_name = "N", env = "HLD_HASH_THREADS")]
+    pub hash_threads: Option<usize>,
+
+    /// Maximum number of files read concurrently on each device
+    #[arg(long, value_name = "N", env = "HLD_IO_THREADS_PER_DEVICE")]
+    pub io_threads_per_device: Option<NonZeroUsize>,
+
+    /// Display the progress on stderr
+    #[arg(long, env = "HLD_PROGRESS")]
+    pub progress: bool,
+
     /// Log level
     #[arg(short = 'l', long, default_value_t = Level::Info, env = "HLD_LOG_LEVEL")]
     pub log_level: Level,
@@ -50,6 +163,13 @@ pub struct Config {
     pub completion: Option<Shell>,
 }
 
+impl Config {
+    /// the file where the digests are saved when the run is interrupted
+    pub fn checkpoint_path(&self) -> PathBuf {
+        self.cache_path.with_extension("checkpoint")
+    }
+}
+
 #[derive(ValueEnum, Clone, Debug, Display)]
 #[strum(serialize_all = "lowercase")]
 pub enum Level {
@@ -72,6 +192,29 @@ impl From<Level> for log::Level {
     }
 }
 
+/// parse a duration made of a number and an optional unit: s, m, h or d
+fn parse_duration(value: &str) -> Result<Duration, String> {
+    let (number, unit) = value.split_at(
+        value
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(value.len()),
+    );
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("invalid duration: {value}"))?;
+    let factor = match unit {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return Err(format!("invalid duration unit: {unit}")),
+    };
+    number
+        .checked_mul(factor)
+        .map(Duration::from_secs)
+        .ok_or_else(|| format!("invalid duration: {value}"))
+}
+
 pub fn defaut_cache_path() -> PathBuf {
     let mut path = ProjectDirs::from("com", "glehmann", "hld")
         .unwrap()
@@ -0,0 +1,480 @@
+use crate::cli::Config;
+use crate::error::{Error, IOResultExt, Result};
+use crate::extent::data_segments;
+use crate::limiter::DeviceLimiter;
+use crate::stamp::FileStamp;
+use clap::ValueEnum;
+use rayon::{ThreadPool, ThreadPoolBuilder};
+use serde::{Deserialize, Serialize};
+use sha2::Digest as _;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::io::{BufRead, Read, Seek, Write};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Mutex};
+use std::time::SystemTime;
+use strum::Display;
+use xxhash_rust::xxh3::Xxh3;
+
+/// the size above which the files are memory mapped and hashed in parallel
+const MMAP_THRESHOLD: u64 = 4 * 1024 * 1024;
+
+/// the size of the buffer used to read the files with the buffered I/O
+const BUFFER_SIZE: usize = 256 * 1024;
+
+/// the way the content of the files is read to compute their digest
+#[derive(Debug, Eq, PartialEq, Copy, ValueEnum, Clone, Display)]
+#[value(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
+pub enum HashIo {
+    // memory map the large files with --min-age, and read all the files otherwise
+    Auto,
+    // memory map the files and hash them in parallel
+    Mmap,
+    // read the files sequentially through a large buffer
+    Buffered,
+}
+
+/// the algorithm used to compute the digests
+#[derive(Debug, Eq, PartialEq, Copy, ValueEnum, Clone, Display)]
+#[value(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
+pub enum Algorithm {
+    // BLAKE3 - fast and cryptographic
+    Blake3,
+    // SHA-1 - slow and not collision resistant, but still published by Maven repositories
+    Sha1,
+    // SHA-256 - slower, but widely published
+    Sha256,
+    // xxh3-128 - very fast, but not cryptographic
+    Xxh3,
+}
+
+/// the digest of a file, along with the algorithm that produced it
+#[derive(Debug, Eq, PartialEq, Hash, Copy, Clone, Serialize, Deserialize)]
+pub enum Digest {
+    Blake3([u8; 32]),
+    Sha1([u8; 20]),
+    Sha256([u8; 32]),
+    Xxh3([u8; 16]),
+}
+
+impl Digest {
+    /// parse the hexadecimal representation of a digest
+    pub fn from_hex(algorithm: Algorithm, hex: &str) -> Option<Digest> {
+        if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+            return None;
+        }
+        let bytes = (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+            .collect::<Option<Vec<u8>>>()?;
+        Some(match algorithm {
+            Algorithm::Blake3 => Digest::Blake3(bytes.try_into().ok()?),
+            Algorithm::Sha1 => Digest::Sha1(bytes.try_into().ok()?),
+            Algorithm::Sha256 => Digest::Sha256(bytes.try_into().ok()?),
+            Algorithm::Xxh3 => Digest::Xxh3(bytes.try_into().ok()?),
+        })
+    }
+
+    pub fn algorithm(&self) -> Algorithm {
+        match self {
+            Digest::Blake3(_) => Algorithm::Blake3,
+            Digest::Sha1(_) => Algorithm::Sha1,
+            Digest::Sha256(_) => Algorithm::Sha256,
+            Digest::Xxh3(_) => Algorithm::Xxh3,
+        }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        match self {
+            Digest::Blake3(bytes) | Digest::Sha256(bytes) => bytes,
+            Digest::Sha1(bytes) => bytes,
+            Digest::Xxh3(bytes) => bytes,
+        }
+    }
+}
+
+impl fmt::Display for Digest {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for byte in self.as_bytes() {
+            write!(f, "{byte:02x}")?;
+        }
+        Ok(())
+    }
+}
+
+/// an incremental hasher for any of the supported algorithms
+enum Hasher {
+    Blake3(Box<blake3::Hasher>),
+    Sha1(sha1::Sha1),
+    Sha256(sha2::Sha256),
+    Xxh3(Box<Xxh3>),
+}
+
+impl Hasher {
+    fn new(algorithm: Algorithm) -> Hasher {
+        match algorithm {
+            Algorithm::Blake3 => Hasher::Blake3(Box::new(blake3::Hasher::new())),
+            Algorithm::Sha1 => Hasher::Sha1(sha1::Sha1::new()),
+            Algorithm::Sha256 => Hasher::Sha256(sha2::Sha256::new()),
+            Algorithm::Xxh3 => Hasher::Xxh3(Box::new(Xxh3::new())),
+        }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Blake3(hasher) => {
+                hasher.update(data);
+            }
+            Hasher::Sha1(hasher) => hasher.update(data),
+            Hasher::Sha256(hasher) => hasher.update(data),
+            Hasher::Xxh3(hasher) => hasher.update(data),
+        }
+    }
+
+    /// hash the memory mapped content of a file - in parallel when the algorithm
+    /// allows it
+    fn update_mmap(&mut self, path: &Path) -> io::Result<()> {
+        if let Hasher::Blake3(hasher) = self {
+            hasher.update_mmap_rayon(path)?;
+            return Ok(());
+        }
+        let file = fs::File::open(path)?;
+        if file.metadata()?.len() > 0 {
+            // safety: the files are not supposed to be modified while hld is running
+            let mmap = unsafe { memmap2::Mmap::map(&file)? };
+            self.update(&mmap);
+        }
+        Ok(())
+    }
+
+    fn finalize(self) -> Digest {
+        match self {
+            Hasher::Blake3(hasher) => Digest::Blake3(*hasher.finalize().as_bytes()),
+            Hasher::Sha1(hasher) => Digest::Sha1(hasher.finalize().into()),
+            Hasher::Sha256(hasher) => Digest::Sha256(hasher.finalize().into()),
+            Hasher::Xxh3(hasher) => Digest::Xxh3(hasher.digest128().to_be_bytes()),
+        }
+    }
+}
+
+impl Write for Hasher {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// compute the digests of the files
+pub struct Digester {
+    algorithm: Algorithm,
+    hash_io: HashIo,
+    hash_pool: ThreadPool,
+    limiter: DeviceLimiter,
+    sidecar: bool,
+    /// the digests of the manifests, with the path and modification time of their manifest
+    manifest: HashMap<PathBuf, (Digest, PathBuf, SystemTime)>,
+    resumed: Checkpoint,
+    computed: Mutex<Checkpoint>,
+}
+
+/// the digests computed in a run, with the stamp of the file at that time
+pub type Checkpoint = HashMap<PathBuf, (FileStamp, Digest)>;
+
+impl Digester {
+    pub fn new(config: &Config) -> Result<Digester> {
+        if let Some(hash_threads) = config.hash_threads {
+            debug!("using {hash_threads} hashing threads at most");
+        }
+        if let Some(device_threads) = config.io_threads_per_device {
+            debug!("using {device_threads} I/O threads per device at most");
+        }
+        let hash_threads = config
+            .hash_threads
+            .or(config.io_threads)
+            .or(config.parallel)
+            .unwrap_or(0);
+        debug!("using the {} digest algorithm", config.digest);
+        let mut manifest = HashMap::new();
+        for path in &config.digest_manifests {
+            let modified = fs::metadata(path)
+                .and_then(|m| m.modified())
+                .path_ctx(path)?;
+            manifest.extend(
+                read_digests(config.digest, path)?
+                    .into_iter()
+                    .map(|(file, digest)| (file, (digest, path.clone(), modified))),
+            );
+        }
+        let resumed = if config.resume {
+            read_checkpoint(&config.checkpoint_path())?
+        } else {
+            HashMap::new()
+        };
+        Ok(Digester {
+            algorithm: config.digest,
+            // a file truncated by another process while it is mapped kills hld with
+            // SIGBUS - only map the files when the recently modified ones, the most
+            // likely to be truncated, are skipped
+            hash_io: match config.hash_io {
+                HashIo::Auto if config.min_age.is_none() => HashIo::Buffered,
+                hash_io => hash_io,
+            },
+            hash_pool: ThreadPoolBuilder::new().num_threads(hash_threads).build()?,
+            limiter: DeviceLimiter::new(config.io_threads_per_device),
+            sidecar: config.digest_sidecar,
+            manifest,
+            resumed,
+            computed: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// the digests computed so far, with the stamp of their file at that time
+    pub fn computed_digests(&self) -> Checkpoint {
+        self.computed.lock().unwrap().clone()
+    }
+
+    /// save the digests computed so far, so an interrupted run can be resumed
+    pub fn save_checkpoint(&self, path: &Path) -> Result<()> {
+        let mut checkpoint = self.resumed.clone();
+        checkpoint.extend(self.computed_digests());
+        info!(
+            "saving {} digests in the checkpoint {}",
+            checkpoint.len(),
+            path.display()
+        );
+        let output_file = fs::File::create(path).path_ctx(path)?;
+        bincode::serialize_into(io::BufWriter::new(&output_file), &checkpoint)?;
+        Ok(())
+    }
+
+    pub fn algorithm(&self) -> Algorithm {
+        self.algorithm
+    }
+
+    /// compute the digest of a file
+    ///
+    /// The digest found in the resumed checkpoint, in the manifests or in a
+    /// sidecar file is used if available. Otherwise the file is read in the
+    /// calling thread, while the memory mapped data is hashed in the hashing
+    /// thread pool.
+    pub fn file_digest(&self, path: &Path) -> Result<Digest> {
+        if let Some(digest) = self.checkpointed_digest(path)? {
+            debug!("using the checkpointed digest of {}", path.display());
+            return Ok(digest);
+        }
+        if let Some(digest) = self.external_digest(path) {
+            debug!("using the external digest of {}", path.display());
+            return Ok(digest);
+        }
+        debug!("computing digest of {}", path.display());
+        let file = fs::File::open(path).path_ctx(path)?;
+        let metadata = file.metadata().path_ctx(path)?;
+        let digest = self.read_digest(path, file, &metadata)?;
+        self.record(path, FileStamp::from(&metadata), digest);
+        Ok(digest)
+    }
+
+    /// record a digest found in this run, in order to save it in the checkpoint
+    /// or the cache
+    pub fn record(&self, path: &Path, stamp: FileStamp, digest: Digest) {
+        self.computed
+            .lock()
+            .unwrap()
+            .insert(path.to_owned(), (stamp, digest));
+    }
+
+    /// hash the content of an opened file
+    fn read_digest(&self, path: &Path, file: fs::File, metadata: &fs::Metadata) -> Result<Digest> {
+        if metadata.blocks() * 512 < metadata.len() {
+            trace!("using sparse I/O to hash {}", path.display());
+            let _permit = self.limiter.acquire(metadata.dev());
+            return self.read_sparse_digest(file, metadata.len()).path_ctx(path);
+        }
+        let hash_io = match self.hash_io {
+            HashIo::Auto if metadata.len() >= MMAP_THRESHOLD => HashIo::Mmap,
+            HashIo::Auto => HashIo::Buffered,
+            hash_io => hash_io,
+        };
+        trace!("using {hash_io} I/O to hash {}", path.display());
+        let _permit = self.limiter.acquire(metadata.dev());
+        match hash_io {
+            HashIo::Mmap => {
+                // wait on a channel rather than with ThreadPool::install(), which would
+                // let this thread steal some other work - possibly waiting for the
+                // device permit it is holding
+                let (sender, receiver) = mpsc::channel();
+                let mmap_path = path.to_path_buf();
+                let algorithm = self.algorithm;
+                self.hash_pool.spawn(move || {
+                    let mut hasher = Hasher::new(algorithm);
+                    let res = hasher.update_mmap(&mmap_path).map(|_| hasher.finalize());
+                    let _ = sender.send(res);
+                });
+                receiver.recv().unwrap().path_ctx(path)
+            }
+            _ => {
+                advise_sequential(&file);
+                let mut hasher = Hasher::new(self.algorithm);
+                let mut reader = io::BufReader::with_capacity(BUFFER_SIZE, file);
+                io::copy(&mut reader, &mut hasher).path_ctx(path)?;
+                Ok(hasher.finalize())
+            }
+        }
+    }
+
+    /// hash the content of a sparse file, only reading its data segments - the
+    /// holes are hashed as the zeros they contain
+    fn read_sparse_digest(&self, mut file: fs::File, len: u64) -> io::Result<Digest> {
+        advise_sequential(&file);
+        let mut hasher = Hasher::new(self.algorithm);
+        let zeros = vec![0; BUFFER_SIZE];
+        let hash_zeros = |hasher: &mut Hasher, mut size: u64| {
+            while size > 0 {
+                let chunk = size.min(BUFFER_SIZE as u64);
+                hasher.update(&zeros[..chunk as usize]);
+                size -= chunk;
+            }
+        };
+        let mut offset = 0;
+        for (start, end) in data_segments(&file, len)? {
+            hash_zeros(&mut hasher, start - offset);
+            file.seek(io::SeekFrom::Start(start))?;
+            let mut reader = io::BufReader::with_capacity(BUFFER_SIZE, (&file).take(end - start));
+            io::copy(&mut reader, &mut hasher)?;
+            offset = end;
+        }
+        hash_zeros(&mut hasher, len - offset);
+        Ok(hasher.finalize())
+    }
+
+    /// find the digest of a file in the resumed checkpoint, if the file hasn't
+    /// changed since then
+    fn checkpointed_digest(&self, path: &Path) -> Result<Option<Digest>> {
+        let Some((stamp, digest)) = self.resumed.get(path) else {
+            return Ok(None);
+        };
+        let metadata = fs::metadata(path).path_ctx(path)?;
+        Ok(
+            (digest.algorithm() == self.algorithm && *stamp == FileStamp::from(&metadata))
+                .then_some(*digest),
+        )
+    }
+
+    /// find the digest of a file in the manifests, or in its sidecar file
+    ///
+    /// The manifests and the sidecar files older than the file are ignored, as
+    /// their digest may be the one of a previous content.
+    fn external_digest(&self, path: &Path) -> Option<Digest> {
+        if let Some((digest, manifest, modified)) = self.manifest.get(path) {
+            return saved_after(manifest, *modified, path).then_some(*digest);
+        }
+        if !self.sidecar {
+            return None;
+        }
+        let mut sidecar = path.as_os_str().to_owned();
+        sidecar.push(format!(".{}", self.algorithm));
+        let sidecar = PathBuf::from(sidecar);
+        let content = match fs::metadata(&sidecar).and_then(|m| {
+            let modified = m.modified()?;
+            Ok((modified, fs::read_to_string(&sidecar)?))
+        }) {
+            Ok((modified, _)) if !saved_after(&sidecar, modified, path) => return None,
+            Ok((_, content)) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return None,
+            Err(e) => {
+                warn!("{}: {e}", sidecar.display());
+                return None;
+            }
+        };
+        // the digest may be followed by the file name
+        let digest = content
+            .split_whitespace()
+            .next()
+            .and_then(|hex| Digest::from_hex(self.algorithm, hex));
+        if digest.is_none() {
+            warn!("{}: invalid {} digest", sidecar.display(), self.algorithm);
+        }
+        digest
+    }
+}
+
+/// is a digest file at least as recent as the file it describes
+fn saved_after(digests: &Path, saved: SystemTime, path: &Path) -> bool {
+    let fresh = fs::metadata(path)
+        .and_then(|m| m.modified())
+        .is_ok_and(|modified| saved >= modified);
+    if !fresh {
+        warn!(
+            "{}: older than {} - computing its digest",
+            digests.display(),
+            path.display()
+        );
+    }
+    fresh
+}
+
+/// read the digests saved by an interrupted run
+fn read_checkpoint(path: &Path) -> Result<Checkpoint> {
+    match fs::File::open(path) {
+        Ok(file) => {
+            debug!("reading the checkpoint {}", path.display());
+            Ok(bincode::deserialize_from(io::BufReader::new(file))?)
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            warn!("{}: no checkpoint to resume from", path.display());
+            Ok(HashMap::new())
+        }
+        Err(e) => Err(e).path_ctx(path),
+    }
+}
+
+/// read a file with a digest per line, in the format used by sha256sum and b3sum
+///
+/// The relative paths are relative to the current directory, as with `sha256sum -c`.
+pub fn read_digests(algorithm: Algorithm, path: &Path) -> Result<HashMap<PathBuf, Digest>> {
+    debug!("reading the {algorithm} digests in {}", path.display());
+    let reader = io::BufReader::new(fs::File::open(path).path_ctx(path)?);
+    let mut digests = HashMap::new();
+    for (i, line) in reader.lines().enumerate() {
+        let line = line.path_ctx(path)?;
+        if line.is_empty() {
+            continue;
+        }
+        let (digest, file) = parse_digest_line(algorithm, &line).ok_or(Error::DigestLine {
+            path: path.to_owned(),
+            line: i + 1,
+        })?;
+        digests.insert(file, digest);
+    }
+    Ok(digests)
+}
+
+/// parse a `<hex>  <path>` line - or `<hex> *<path>` for the files read in binary mode
+fn parse_digest_line(algorithm: Algorithm, line: &str) -> Option<(Digest, PathBuf)> {
+    let (hex, file) = line.split_once(' ')?;
+    let file = file.strip_prefix([' ', '*'])?;
+    Some((Digest::from_hex(algorithm, hex)?, PathBuf::from(file)))
+}
+
+/// let the kernel know that the file is going to be read sequentially, so it can
+/// read ahead more aggressively
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn advise_sequential(file: &fs::File) {
+    use std::os::unix::io::AsRawFd;
+    // this is only a hint, so the result is ignored
+    unsafe {
+        libc::posix_fadvise(file.as_raw_fd(), 0, 0, libc::POSIX_FADV_SEQUENTIAL);
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn advise_sequential(_file: &fs::File) {}
@@ -1,12 +1,24 @@
+use std::collections::BTreeMap;
 use std::io;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::result;
+use std::sync::Mutex;
+use strum::Display;
 use thiserror::Error;
 
 #[derive(Debug, Error)]
 pub enum Error {
     #[error("{path}: {source}")]
     PathIo { source: io::Error, path: PathBuf },
+    #[error("{path}:{line}: invalid digest line")]
+    DigestLine { path: PathBuf, line: usize },
+    #[error("{path}: {source}")]
+    ConfigFile {
+        source: toml::de::Error,
+        path: PathBuf,
+    },
+    #[error("{path}: unknown profile {name}")]
+    UnknownProfile { name: String, path: PathBuf },
     #[error("{glob}: {source}")]
     GlobPattern {
         source: glob::PatternError,
@@ -16,10 +28,66 @@ pub enum Error {
     Glob(#[from] glob::GlobError),
     #[error(transparent)]
     Cache(#[from] bincode::Error),
+    #[error("{path}: corrupted cache: {source} - use --clear-cache to start a new one")]
+    CorruptedCache {
+        source: bincode::Error,
+        path: PathBuf,
+    },
     #[error(transparent)]
     Logger(#[from] log::SetLoggerError),
     #[error(transparent)]
     ThreadPool(#[from] rayon::ThreadPoolBuildError),
+    #[error(transparent)]
+    Signal(#[from] ctrlc::Error),
+    #[error("{path}: timeout while waiting for the cache lock held {holder}")]
+    LockTimeout { path: PathBuf, holder: String },
+    #[error("interrupted")]
+    Interrupted,
+    #[error("{0} files skipped after an error")]
+    Incomplete(usize),
+}
+
+/// the exit codes of hld
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum ExitCode {
+    Success = 0,
+    /// any other error
+    Failure = 1,
+    /// an invalid command line, configuration file, glob or digest file
+    Usage = 2,
+    /// some files were skipped after an error, with `--keep-going`
+    Partial = 3,
+    CorruptedCache = 4,
+    /// nothing to deduplicate, with `--detailed-exit-codes`
+    NothingToDo = 5,
+    /// the cache is still locked after `--lock-timeout`
+    LockTimeout = 6,
+    Interrupted = 130,
+}
+
+impl Error {
+    pub fn exit_code(&self) -> ExitCode {
+        match self {
+            Error::DigestLine { .. }
+            | Error::GlobPattern { .. }
+            | Error::ConfigFile { .. }
+            | Error::UnknownProfile { .. } => ExitCode::Usage,
+            Error::Incomplete(_) => ExitCode::Partial,
+            Error::CorruptedCache { .. } => ExitCode::CorruptedCache,
+            Error::LockTimeout { .. } => ExitCode::LockTimeout,
+            Error::Interrupted => ExitCode::Interrupted,
+            _ => ExitCode::Failure,
+        }
+    }
+
+    /// the file and the kind of the errors only affecting a single file
+    fn file_error(&self) -> Option<(&Path, io::ErrorKind)> {
+        match self {
+            Error::PathIo { source, path } => Some((path, source.kind())),
+            Error::Glob(e) => Some((e.path(), e.error().kind())),
+            _ => None,
+        }
+    }
 }
 
 /// Alias for a `Result` with the error type `hld::Error`.
@@ -52,3 +120,59 @@ impl<T> GlobResultExt<T> for result::Result<T, glob::PatternError> {
         })
     }
 }
+
+/// the step of the run where a file was skipped after an error
+#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Copy, Clone, Display)]
+pub enum Step {
+    #[strum(serialize = "discovering")]
+    Discovery,
+    #[strum(serialize = "hashing")]
+    Hashing,
+    #[strum(serialize = "linking")]
+    Linking,
+}
+
+/// the files skipped after an error, with the step and the kind of their first error
+static FAILURES: Mutex<BTreeMap<PathBuf, (Step, io::ErrorKind)>> = Mutex::new(BTreeMap::new());
+
+/// Extension trait to skip the files with an error.
+pub trait KeepGoingExt<T> {
+    /// record the error and return `None` when it only affects a single file and
+    /// `keep_going` is set
+    fn keep_going(self, keep_going: bool, step: Step) -> Result<Option<T>>;
+}
+
+impl<T> KeepGoingExt<T> for Result<T> {
+    fn keep_going(self, keep_going: bool, step: Step) -> Result<Option<T>> {
+        match self {
+            Ok(value) => Ok(Some(value)),
+            Err(err) => match err.file_error() {
+                Some((path, kind)) if keep_going => {
+                    // the files may be walked several times - only report them once
+                    let mut failures = FAILURES.lock().unwrap();
+                    if !failures.contains_key(path) {
+                        warn!("{err}");
+                        failures.insert(path.to_owned(), (step, kind));
+                    }
+                    Ok(None)
+                }
+                _ => Err(err),
+            },
+        }
+    }
+}
+
+/// report the number of files skipped after an error, by step and kind of error
+///
+/// Returns the number of files skipped.
+pub fn report_failures() -> usize {
+    let failures = FAILURES.lock().unwrap();
+    let mut counts: BTreeMap<(Step, String), usize> = BTreeMap::new();
+    for (step, kind) in failures.values() {
+        *counts.entry((*step, kind.to_string())).or_default() += 1;
+    }
+    for ((step, kind), count) in counts {
+        warn!("{count} files skipped while {step}: {kind}");
+    }
+    failures.len()
+}
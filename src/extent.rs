@@ -0,0 +1,338 @@
+use crate::error::{IOResultExt, Result};
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::path::Path;
+
+/// a physical extent of a file
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub struct Extent {
+    pub logical: u64,
+    pub physical: u64,
+    pub length: u64,
+}
+
+/// the physical address of a range of a file, if it is in a single known extent
+pub fn physical_offset(extents: &[Extent], offset: u64, len: u64) -> Option<u64> {
+    extents
+        .iter()
+        .find(|e| e.logical <= offset && offset + len <= e.logical + e.length)
+        .map(|e| e.physical + offset - e.logical)
+}
+
+/// do the two files share all their extents, for example after a reflink
+///
+/// The files whose extents location is not known, or on a filesystem without
+/// FIEMAP support, are considered as not shared.
+pub fn share_extents(path1: &Path, path2: &Path) -> Result<bool> {
+    let Some(extents1) = extents(path1)? else {
+        return Ok(false);
+    };
+    let Some(extents2) = extents(path2)? else {
+        return Ok(false);
+    };
+    Ok(extents1 == extents2)
+}
+
+/// the physical extents of a file, merged when contiguous, or None if their
+/// location is not known
+pub fn extents(path: &Path) -> Result<Option<Vec<Extent>>> {
+    let file = File::open(path).path_ctx(path)?;
+    let Some(raw_extents) = fiemap(&file).path_ctx(path)? else {
+        return Ok(None);
+    };
+    let mut extents: Vec<Extent> = vec![];
+    for (extent, flags) in raw_extents {
+        if flags & FIEMAP_EXTENT_UNKNOWN_LOCATION != 0 {
+            return Ok(None);
+        }
+        match extents.last_mut() {
+            Some(last)
+                if last.logical + last.length == extent.logical
+                    && last.physical + last.length == extent.physical =>
+            {
+                last.length += extent.length
+            }
+            _ => extents.push(extent),
+        }
+    }
+    Ok(Some(extents))
+}
+
+/// the extents flags telling that their location can't be compared
+const FIEMAP_EXTENT_UNKNOWN_LOCATION: u32 = 0x0002 // FIEMAP_EXTENT_UNKNOWN
+    | 0x0004 // FIEMAP_EXTENT_DELALLOC
+    | 0x0008 // FIEMAP_EXTENT_ENCODED
+    | 0x0200 // FIEMAP_EXTENT_DATA_INLINE
+    | 0x0400; // FIEMAP_EXTENT_DATA_TAIL
+
+#[cfg(target_os = "linux")]
+fn fiemap(file: &File) -> io::Result<Option<Vec<(Extent, u32)>>> {
+    use std::os::unix::io::AsRawFd;
+
+    const FS_IOC_FIEMAP: libc::c_ulong = 0xC020_660B;
+    const FIEMAP_FLAG_SYNC: u32 = 0x0001;
+    const FIEMAP_EXTENT_LAST: u32 = 0x0001;
+    const EXTENT_COUNT: usize = 256;
+
+    #[repr(C)]
+    #[derive(Default, Copy, Clone)]
+    struct FiemapExtent {
+        fe_logical: u64,
+        fe_physical: u64,
+        fe_length: u64,
+        fe_reserved64: [u64; 2],
+        fe_flags: u32,
+        fe_reserved: [u32; 3],
+    }
+
+    #[repr(C)]
+    struct Fiemap {
+        fm_start: u64,
+        fm_length: u64,
+        fm_flags: u32,
+        fm_mapped_extents: u32,
+        fm_extent_count: u32,
+        fm_reserved: u32,
+        fm_extents: [FiemapExtent; EXTENT_COUNT],
+    }
+
+    let mut extents = vec![];
+    let mut start = 0;
+    loop {
+        let mut fiemap = Fiemap {
+            fm_start: start,
+            fm_length: u64::MAX - start,
+            fm_flags: FIEMAP_FLAG_SYNC,
+            fm_mapped_extents: 0,
+            fm_extent_count: EXTENT_COUNT as u32,
+            fm_reserved: 0,
+            fm_extents: [FiemapExtent::default(); EXTENT_COUNT],
+        };
+        // SAFETY: the buffer has room for the number of extents it declares
+        let res = unsafe { libc::ioctl(file.as_raw_fd(), FS_IOC_FIEMAP as _, &mut fiemap) };
+        if res < 0 {
+            let err = io::Error::last_os_error();
+            return match err.raw_os_error() {
+                Some(libc::EOPNOTSUPP) | Some(libc::ENOTTY) => Ok(None),
+                _ => Err(err),
+            };
+        }
+        let mapped = &fiemap.fm_extents[..fiemap.fm_mapped_extents as usize];
+        for extent in mapped {
+            extents.push((
+                Extent {
+                    logical: extent.fe_logical,
+                    physical: extent.fe_physical,
+                    length: extent.fe_length,
+                },
+                extent.fe_flags,
+            ));
+        }
+        match mapped.last() {
+            Some(last) if last.fe_flags & FIEMAP_EXTENT_LAST == 0 => {
+                start = last.fe_logical + last.fe_length
+            }
+            _ => return Ok(Some(extents)),
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn fiemap(_file: &File) -> io::Result<Option<Vec<(Extent, u32)>>> {
+    Ok(None)
+}
+
+/// the data segments of a file, as start and end offsets - the rest being holes
+///
+/// The whole file is a single data segment on the filesystems without support
+/// for the holes.
+pub fn data_segments(file: &File, len: u64) -> io::Result<Vec<(u64, u64)>> {
+    let mut segments = vec![];
+    let mut offset = 0;
+    while offset < len {
+        let Some(start) = seek(file, offset, Whence::Data)? else {
+            break;
+        };
+        let end = seek(file, start, Whence::Hole)?.unwrap_or(len).min(len);
+        segments.push((start, end));
+        offset = end;
+    }
+    Ok(segments)
+}
+
+enum Whence {
+    Data,
+    Hole,
+}
+
+/// the next data or hole offset, or None if there is no more data
+#[cfg(target_os = "linux")]
+fn seek(file: &File, offset: u64, whence: Whence) -> io::Result<Option<u64>> {
+    use std::os::unix::io::AsRawFd;
+
+    let whence = match whence {
+        Whence::Data => libc::SEEK_DATA,
+        Whence::Hole => libc::SEEK_HOLE,
+    };
+    // SAFETY: lseek has no memory safety requirement
+    let res = unsafe { libc::lseek(file.as_raw_fd(), offset as libc::off_t, whence) };
+    if res >= 0 {
+        return Ok(Some(res as u64));
+    }
+    let err = io::Error::last_os_error();
+    match err.raw_os_error() {
+        Some(libc::ENXIO) => Ok(None),
+        // no support for the holes: the whole file is data
+        Some(libc::EINVAL) if whence == libc::SEEK_DATA => Ok(Some(offset)),
+        Some(libc::EINVAL) => Ok(None),
+        _ => Err(err),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn seek(_file: &File, offset: u64, whence: Whence) -> io::Result<Option<u64>> {
+    Ok(match whence {
+        Whence::Data => Some(offset),
+        Whence::Hole => None,
+    })
+}
+
+/// the result of a kernel deduplication
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum Dedupe {
+    /// the extents are now shared
+    Done,
+    /// the content of the files is not the same
+    Differs,
+    /// the filesystem doesn't support the kernel deduplication
+    Unsupported,
+}
+
+/// share the extents of the source file with the destination file with the
+/// FIDEDUPERANGE ioctl
+///
+/// The kernel only shares the ranges with the exact same content, atomically,
+/// and without changing the destination inode or its metadata.
+pub fn dedupe(src: &Path, dest: &Path) -> Result<Dedupe> {
+    let src_file = File::open(src).path_ctx(src)?;
+    let len = src_file.metadata().path_ctx(src)?.len();
+    let dest_file = open_dest(dest)?;
+    dedupe_range(&src_file, 0, &dest_file, 0, len).path_ctx(dest)
+}
+
+/// open the destination of a kernel deduplication - the kernel accepts a read
+/// only file from its owner
+pub fn open_dest(path: &Path) -> Result<File> {
+    match OpenOptions::new().read(true).write(true).open(path) {
+        Err(e) if e.kind() == io::ErrorKind::PermissionDenied => File::open(path),
+        res => res,
+    }
+    .path_ctx(path)
+}
+
+/// share a range of the source file with a range of the destination file, if
+/// their content is the same
+#[cfg(target_os = "linux")]
+pub fn dedupe_range(
+    src: &File,
+    src_offset: u64,
+    dest: &File,
+    dest_offset: u64,
+    len: u64,
+) -> io::Result<Dedupe> {
+    use std::os::unix::io::AsRawFd;
+
+    const FIDEDUPERANGE: libc::c_ulong = 0xC018_9436;
+    const FILE_DEDUPE_RANGE_DIFFERS: i32 = 1;
+
+    #[repr(C)]
+    struct FileDedupeRangeInfo {
+        dest_fd: i64,
+        dest_offset: u64,
+        bytes_deduped: u64,
+        status: i32,
+        reserved: u32,
+    }
+
+    #[repr(C)]
+    struct FileDedupeRange {
+        src_offset: u64,
+        src_length: u64,
+        dest_count: u16,
+        reserved1: u16,
+        reserved2: u32,
+        info: [FileDedupeRangeInfo; 1],
+    }
+
+    // the kernel may deduplicate less than requested, so loop until the end
+    let mut offset = 0;
+    while offset < len {
+        let mut range = FileDedupeRange {
+            src_offset: src_offset + offset,
+            src_length: len - offset,
+            dest_count: 1,
+            reserved1: 0,
+            reserved2: 0,
+            info: [FileDedupeRangeInfo {
+                dest_fd: dest.as_raw_fd() as i64,
+                dest_offset: dest_offset + offset,
+                bytes_deduped: 0,
+                status: 0,
+                reserved: 0,
+            }],
+        };
+        // SAFETY: the range has room for the number of destinations it declares
+        let res = unsafe { libc::ioctl(src.as_raw_fd(), FIDEDUPERANGE as _, &mut range) };
+        let status = if res < 0 {
+            -io::Error::last_os_error().raw_os_error().unwrap_or(0)
+        } else {
+            range.info[0].status
+        };
+        match status {
+            FILE_DEDUPE_RANGE_DIFFERS => return Ok(Dedupe::Differs),
+            status if status < 0 => {
+                return match -status {
+                    libc::EOPNOTSUPP | libc::ENOTTY | libc::EINVAL | libc::EXDEV => {
+                        Ok(Dedupe::Unsupported)
+                    }
+                    errno => Err(io::Error::from_raw_os_error(errno)),
+                }
+            }
+            _ if range.info[0].bytes_deduped == 0 => break,
+            _ => offset += range.info[0].bytes_deduped,
+        }
+    }
+    Ok(Dedupe::Done)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn dedupe_range(
+    _src: &File,
+    _src_offset: u64,
+    _dest: &File,
+    _dest_offset: u64,
+    _len: u64,
+) -> io::Result<Dedupe> {
+    Ok(Dedupe::Unsupported)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use assert_fs::prelude::*;
+
+    #[test]
+    fn shared_extents() {
+        let tmp = assert_fs::TempDir::new().unwrap();
+        let foo = tmp.child("foo");
+        let bar = tmp.child("bar");
+        foo.write_binary(&[1; 100_000]).unwrap();
+        bar.write_binary(&[1; 100_000]).unwrap();
+        // a copy has its own extents
+        assert!(!share_extents(foo.path(), bar.path()).unwrap());
+        // unlike the file itself, where the filesystem reports them
+        if extents(foo.path()).unwrap().is_some() {
+            assert!(share_extents(foo.path(), foo.path()).unwrap());
+        }
+    }
+}
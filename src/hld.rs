@@ -1,204 +1,838 @@
+use crate::cache::{log_stats, save_computed_digests, update_cache, CachedDigests};
 use crate::cli::*;
-use crate::error::{GlobResultExt, IOResultExt, Result};
+use crate::digest::{Digest, Digester};
+use crate::error::{Error, GlobResultExt, IOResultExt, KeepGoingExt, Result, Step};
+use crate::extent::{dedupe, share_extents, Dedupe};
+use crate::partial;
+use crate::progress::PROGRESS;
+use crate::signal;
+use crate::stamp::FileStamp;
 use crate::strategy::Strategy;
-use blake3::{Hash, Hasher};
-use fs2::FileExt;
 use itertools::chain;
 use rayon::prelude::*;
-use std::collections::HashMap;
-use std::collections::VecDeque;
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsString;
 use std::fs;
 use std::fs::File;
 use std::io;
+use std::io::{BufRead, Write};
 use std::os::unix::fs as ufs;
 use std::os::unix::fs::MetadataExt;
 use std::path::Path;
 use std::path::PathBuf;
-use std::sync::Mutex;
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use std::vec::Vec;
+use strum::Display;
 
-/// compute the digest of a file
-fn file_digest(path: &Path) -> Result<Hash> {
-    debug!("computing digest of {}", path.display());
-    let mut file = fs::File::open(path).path_ctx(path)?;
-    let mut hasher = Hasher::new();
-    io::copy(&mut file, &mut hasher).path_ctx(path)?;
-    Ok(hasher.finalize())
-}
-
-// /// print the file digests
-// fn print_digests(paths: &[PathBuf]) -> Result<()> {
-//     for path in paths {
-//         let sha1 = file_digest(&path)?;
-//         println!("{}  {}", sha1, path.display());
-//     }
-//     println!("{:?}", find_file_duplicates(paths));
-//     Ok(())
-// }
-
-/// find the duplicates in the provided paths
+/// the number of paths buffered between the discovery and the processing of the files
+const CHANNEL_BOUND: usize = 4096;
+
+/// the path used in the errors while writing to the standard output
+const STDOUT: &str = "<stdout>";
+
+/// a candidate file for the deduplication
+struct Candidate {
+    path: PathBuf,
+    stamp: FileStamp,
+    /// the space allocated to the file - less than its size for the sparse files
+    allocated: u64,
+    cached: bool,
+}
+
+impl Candidate {
+    fn inode(&self) -> (u64, u64) {
+        (self.stamp.dev, self.stamp.ino)
+    }
+}
+
+/// find the duplicates in a set of candidate files with the same size
 fn find_file_duplicates<'a>(
     config: &Config,
-    paths: &'a [PathBuf],
-    caches: &'a [PathBuf],
-) -> Result<Vec<Vec<&'a PathBuf>>> {
-    // compute a map of the digests to the path with that digest
-    let ino_map = Mutex::new(HashMap::new());
-    let cache = update_cache(config, caches)?;
-
-    // get some metadata and filter out the empty files
-    let mut path_inos: Vec<(&'a PathBuf, (u64, u64))> = Vec::new();
-    for path in chain(caches, paths) {
-        let metadata = fs::metadata(path).path_ctx(path)?;
-        if metadata.len() > 0 {
-            path_inos.push((path, inos_m(&metadata)));
-        }
-    }
-
-    // compute the digests
-    let digests = path_inos
-        .par_iter()
-        .map(|(path, inode)| -> Result<(&'a PathBuf, Hash)> {
-            let ino_digest: Option<Hash> = ino_map.lock().unwrap().get(inode).copied();
-            let digest = if let Some(digest) = ino_digest {
-                digest
-            } else {
-                let digest = if let Some(digest) = cache.get(*path) {
-                    *digest
+    digester: &Digester,
+    cache: &CachedDigests,
+    candidates: &'a [Candidate],
+) -> Result<Vec<(Option<Digest>, Vec<&'a Candidate>)>> {
+    // no need to compute anything if all the files are already linked together
+    if candidates
+        .iter()
+        .all(|c| c.inode() == candidates[0].inode())
+    {
+        PROGRESS.hashed(candidates[0].stamp.size);
+        return Ok(vec![(None, candidates.iter().collect())]);
+    }
+
+    // compute the digests, only once per inode
+    let mut inodes = hashmap! {};
+    for candidate in candidates {
+        inodes.entry(candidate.inode()).or_insert(candidate);
+    }
+    let ino_map = inodes
+        .into_par_iter()
+        .filter_map(|(inode, candidate)| {
+            let digest = (|| {
+                signal::check()?;
+                let cached = if candidate.cached {
+                    cache.get_stable(&candidate.path)
                 } else {
-                    file_digest(path)?
+                    cache.get(&candidate.path, &candidate.stamp)
                 };
-                ino_map.lock().unwrap().insert(*inode, digest);
-                digest
-            };
-            Ok((path, digest))
+                let digest = if let Some(digest) = cached {
+                    digester.record(&candidate.path, candidate.stamp, digest);
+                    digest
+                } else {
+                    digester.file_digest(&candidate.path)?
+                };
+                PROGRESS.hashed(candidate.stamp.size);
+                Ok(digest)
+            })();
+            digest
+                .keep_going(config.keep_going, Step::Hashing)
+                .map(|digest| digest.map(|digest| (inode, digest)))
+                .transpose()
         })
-        .collect::<Result<Vec<(&'a PathBuf, Hash)>>>()?;
+        .collect::<Result<HashMap<_, _>>>()?;
 
-    // merge the digests in a hashmap
+    // merge the digests in a hashmap - the files that couldn't be hashed are left out
     let mut res = hashmap! {};
-    for (path, digest) in digests {
-        res.entry(digest).or_insert_with(Vec::new).push(path);
+    for candidate in candidates {
+        if let Some(digest) = ino_map.get(&candidate.inode()) {
+            res.entry(*digest).or_insert_with(Vec::new).push(candidate);
+        }
     }
 
     // then just keep the paths with duplicates
     Ok(res
         .into_iter()
         .filter(|(_, v)| v.len() >= 2)
-        .map(|(_, v)| v)
+        .map(|(digest, v)| (Some(digest), v))
         .collect())
 }
 
-fn update_cache(config: &Config, paths: &[PathBuf]) -> Result<HashMap<PathBuf, Hash>> {
-    // locking the cache
-    let cache_dir = config.cache_path.parent().unwrap().to_owned();
-    fs::create_dir_all(&cache_dir).path_ctx(&cache_dir)?;
-    let lock_path = config.cache_path.with_extension("lock");
-    let lock_file = File::create(&lock_path).path_ctx(&lock_path)?;
-    lock_file.lock_exclusive().path_ctx(&lock_path)?;
-
-    let cache: HashMap<PathBuf, Hash> = if config.clear_cache {
-        hashmap! {}
-    } else {
-        File::open(&config.cache_path)
-            .ok()
-            .map_or_else(HashMap::new, |reader| {
-                debug!("reading cache");
-                bincode::deserialize_from(io::BufReader::new(reader)).unwrap_or_default()
-            })
-    };
-    let original_cache_size = cache.len();
+/// stream the files matching the globs and count the files of each size
+///
+/// The cached files are also returned, in order to update the cache.
+fn count_sizes(
+    config: &Config,
+    file_globs: &[String],
+    cache_globs: &[String],
+) -> Result<(HashMap<u64, usize>, Vec<PathBuf>)> {
+    let follow_symlinks = config.follow_symlinks;
+    let (sizes, mut caches) = walk_globs(file_globs, cache_globs, &config.excludes, move |path| {
+        select_file(path, follow_symlinks, true)
+    })
+    .into_iter()
+    .par_bridge()
+    .map(|res| {
+        let (path, cached) = res?;
+        PROGRESS.discovered();
+        let metadata = fs::metadata(&path).path_ctx(&path)?;
+        Ok((path, cached, metadata))
+    })
+    .try_fold(
+        || (HashMap::new(), Vec::new()),
+        |(mut sizes, mut caches), res: Result<(PathBuf, bool, fs::Metadata)>| -> Result<_> {
+            let Some((path, cached, metadata)) =
+                res.keep_going(config.keep_going, Step::Discovery)?
+            else {
+                return Ok((sizes, caches));
+            };
+            if !old_enough(config, &metadata) {
+                return Ok((sizes, caches));
+            }
+            let size = metadata.len();
+            if size > 0 {
+                *sizes.entry(size).or_insert(0) += 1;
+            }
+            if cached {
+                caches.push(path);
+            }
+            Ok((sizes, caches))
+        },
+    )
+    .try_reduce(
+        || (HashMap::new(), Vec::new()),
+        |(mut sizes, mut caches), (other_sizes, other_caches)| {
+            for (size, count) in other_sizes {
+                *sizes.entry(size).or_insert(0) += count;
+            }
+            caches.extend(other_caches);
+            Ok((sizes, caches))
+        },
+    )?;
+    caches.par_sort();
+    caches.dedup();
+    Ok((sizes, caches))
+}
 
-    // remove dead entries
-    let mut live_cache: HashMap<_, _> = cache
-        .into_iter()
-        .collect::<Vec<(_, _)>>()
-        .par_iter()
-        .cloned()
-        .filter(|(path, _)| path.exists())
-        .collect();
-    let live_cache_size = live_cache.len();
-    let updated = original_cache_size != live_cache_size;
-
-    // compute the digest for the entries not already there
-    let new_digests = paths
-        .par_iter()
-        .map(|path| {
-            let digest = live_cache
-                .get(path)
-                .map_or_else(|| file_digest(path), |d| Ok(*d))?;
-            Ok((path.clone(), digest))
+/// stream the files matching the globs and group the ones sharing their size with
+/// another file
+fn find_candidates(
+    config: &Config,
+    file_globs: &[String],
+    cache_globs: &[String],
+    sizes: &HashMap<u64, usize>,
+) -> Result<Vec<Vec<Candidate>>> {
+    let follow_symlinks = config.follow_symlinks;
+    let buckets = walk_globs(file_globs, cache_globs, &config.excludes, move |path| {
+        select_file(path, follow_symlinks, false)
+    })
+    .into_iter()
+    .par_bridge()
+    .map(|res| {
+        let (path, cached) = res?;
+        let metadata = fs::metadata(&path).path_ctx(&path)?;
+        PROGRESS.stated();
+        Ok((path, cached, metadata))
+    })
+    .try_fold(
+        HashMap::new,
+        |mut buckets, res: Result<(PathBuf, bool, fs::Metadata)>| -> Result<_> {
+            let Some((path, cached, metadata)) =
+                res.keep_going(config.keep_going, Step::Discovery)?
+            else {
+                return Ok(buckets);
+            };
+            if !old_enough(config, &metadata) {
+                debug!("skipping the recently modified file {}", path.display());
+                return Ok(buckets);
+            }
+            if sizes.get(&metadata.len()).is_some_and(|count| *count >= 2) {
+                buckets
+                    .entry(metadata.len())
+                    .or_insert_with(Vec::new)
+                    .push(Candidate {
+                        path,
+                        stamp: FileStamp::from(&metadata),
+                        allocated: metadata.blocks() * 512,
+                        cached,
+                    });
+            }
+            Ok(buckets)
+        },
+    )
+    .try_reduce(HashMap::new, |mut buckets, other_buckets| {
+        for (size, candidates) in other_buckets {
+            buckets
+                .entry(size)
+                .or_insert_with(Vec::new)
+                .extend(candidates);
+        }
+        Ok(buckets)
+    })?;
+    Ok(buckets
+        .into_values()
+        .map(|mut candidates| {
+            // a path may be matched by several globs - keep the cached one if any
+            candidates.sort_by(|a, b| a.path.cmp(&b.path).then(b.cached.cmp(&a.cached)));
+            candidates.dedup_by(|a, b| a.path == b.path);
+            // link the files to the cached ones first, as they are supposed to be stable,
+            // and then to the sparsest ones, in order to keep their holes
+            candidates.sort_by(|a, b| {
+                b.cached
+                    .cmp(&a.cached)
+                    .then(a.allocated.cmp(&b.allocated))
+                    .then(a.path.cmp(&b.path))
+            });
+            candidates
         })
-        .collect::<Result<HashMap<_, _>>>()?;
+        .filter(|candidates| candidates.len() >= 2)
+        .collect())
+}
 
-    live_cache.extend(new_digests.clone());
-    let updated = updated || live_cache_size != live_cache.len();
+/// stream the files matching the globs and keep the ones at least as large as
+/// the minimal size
+fn find_large_files(
+    config: &Config,
+    file_globs: &[String],
+    cache_globs: &[String],
+    min_size: u64,
+) -> Result<Vec<PathBuf>> {
+    let follow_symlinks = config.follow_symlinks;
+    let mut paths = walk_globs(file_globs, cache_globs, &config.excludes, move |path| {
+        select_file(path, follow_symlinks, false)
+    })
+    .into_iter()
+    .par_bridge()
+    .filter_map(|res| {
+        let res = res.and_then(|(path, _)| {
+            let metadata = fs::metadata(&path).path_ctx(&path)?;
+            Ok((path, metadata))
+        });
+        match res.keep_going(config.keep_going, Step::Discovery) {
+            Ok(Some((path, metadata)))
+                if metadata.len() >= min_size && old_enough(config, &metadata) =>
+            {
+                Some(Ok(path))
+            }
+            Ok(_) => None,
+            Err(e) => Some(Err(e)),
+        }
+    })
+    .collect::<Result<Vec<_>>>()?;
+    paths.par_sort();
+    paths.dedup();
+    Ok(paths)
+}
 
-    if updated {
-        debug!("saving updated cache with {} entries", live_cache.len());
-        if !config.dry_run {
-            let output_file = File::create(&config.cache_path).path_ctx(&config.cache_path)?;
-            bincode::serialize_into(io::BufWriter::new(&output_file), &live_cache)?;
+/// print the digests of the files, in the format used by sha256sum and b3sum
+pub fn print_digests(config: &Config, file_globs: &[String], cache_globs: &[String]) -> Result<()> {
+    let digester = Digester::new(config)?;
+    let follow_symlinks = config.follow_symlinks;
+    let mut caches = walk_globs(&[], cache_globs, &config.excludes, move |path| {
+        select_file(path, follow_symlinks, false)
+    })
+    .into_iter()
+    .filter_map(|res| {
+        res.map(|(path, _)| path)
+            .keep_going(config.keep_going, Step::Discovery)
+            .transpose()
+    })
+    .collect::<Result<Vec<PathBuf>>>()?;
+    caches.par_sort();
+    caches.dedup();
+    let cache = update_cache(config, &digester, &caches)?;
+    drop(caches);
+    let res = walk_globs(file_globs, cache_globs, &config.excludes, move |path| {
+        select_file(path, follow_symlinks, true)
+    })
+    .into_iter()
+    .par_bridge()
+    .try_for_each(|res| -> Result<()> {
+        signal::check()?;
+        let Some((path, _)) = res.keep_going(config.keep_going, Step::Discovery)? else {
+            return Ok(());
+        };
+        let digest = (|| {
+            let stamp = FileStamp::from(&fs::metadata(&path).path_ctx(&path)?);
+            match cache.get(&path, &stamp) {
+                Some(digest) => Ok(digest),
+                None => digester.file_digest(&path),
+            }
+        })();
+        if let Some(digest) = digest.keep_going(config.keep_going, Step::Hashing)? {
+            writeln!(io::stdout().lock(), "{digest}  {}", path.display()).path_ctx(STDOUT)?;
+        }
+        Ok(())
+    });
+    match res {
+        // the reader of the digests has gone, for example with `| head`
+        Err(Error::PathIo { source, path })
+            if path == Path::new(STDOUT) && source.kind() == io::ErrorKind::BrokenPipe =>
+        {
+            debug!("the output has been closed");
         }
+        res => res?,
     }
-
-    // unlock the cache
-    FileExt::unlock(&lock_file).path_ctx(&config.cache_path)?;
-
-    Ok(new_digests)
+    log_stats();
+    Ok(())
 }
 
 /// find the duplicated files and replace them with hardlinks
-pub fn hardlink_deduplicate(config: &Config, paths: &[PathBuf], caches: &[PathBuf]) -> Result<()> {
-    let dups = find_file_duplicates(config, paths, caches)?;
-    let mut dedup_size: u64 = 0;
-    let mut dedup_files: usize = 0;
-    for dup in dups {
-        dedup_size += file_hardlinks(config, dup[0], &dup[1..])?;
-        dedup_files += dup.len() - 1;
+///
+/// The files are processed in two streaming passes over the globs: the first one
+/// only counts the files of each size, and the second one keeps the files sharing
+/// their size with another file. Only these candidates are kept in memory, hashed
+/// and linked, one size at a time.
+///
+/// When interrupted, the digests computed so far are saved in a checkpoint, in
+/// order to be reused by the next run with `--resume`.
+///
+/// Returns the number of files deduplicated.
+pub fn hardlink_deduplicate(
+    config: &Config,
+    file_globs: &[String],
+    cache_globs: &[String],
+) -> Result<usize> {
+    let digester = Digester::new(config)?;
+    let res = deduplicate(config, &digester, file_globs, cache_globs);
+    // the checkpoint comes first, the cache may be locked by another process
+    if matches!(res, Err(Error::Interrupted)) && !config.dry_run {
+        digester.save_checkpoint(&config.checkpoint_path())?;
+    }
+    if config.cache_all {
+        match save_computed_digests(config, &digester) {
+            // the error of the run is more relevant
+            Err(Error::Interrupted) if res.is_err() => (),
+            Err(e) if res.is_err() => warn!("{e}"),
+            saved => saved?,
+        }
+    }
+    log_stats();
+    match res {
+        Ok(files) if config.resume && !config.dry_run => {
+            let checkpoint_path = config.checkpoint_path();
+            match fs::remove_file(&checkpoint_path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e).path_ctx(checkpoint_path),
+                _ => Ok(files),
+            }
+        }
+        res => res,
+    }
+}
+
+fn deduplicate(
+    config: &Config,
+    digester: &Digester,
+    file_globs: &[String],
+    cache_globs: &[String],
+) -> Result<usize> {
+    let (sizes, caches) = count_sizes(config, file_globs, cache_globs)?;
+    trace!("caches: {caches:?}");
+    let cache = update_cache(config, digester, &caches)?;
+    drop(caches);
+    let buckets = find_candidates(config, file_globs, cache_globs, &sizes)?;
+    drop(sizes);
+    debug!(
+        "{} candidate files for the deduplication",
+        buckets.iter().map(Vec::len).sum::<usize>()
+    );
+    for candidates in &buckets {
+        let inodes: HashSet<_> = candidates.iter().map(Candidate::inode).collect();
+        PROGRESS.to_hash(candidates[0].stamp.size * inodes.len() as u64);
     }
-    debug!("{dedup_size} bytes saved");
-    debug!("{dedup_files} files deduplicated");
+    let savings = buckets
+        .into_par_iter()
+        .map(|candidates| -> Result<Savings> {
+            let mut savings = Savings::default();
+            for (digest, dup) in find_file_duplicates(config, digester, &cache, &candidates)? {
+                PROGRESS.group();
+                let linked = file_hardlinks(config, dup[0], &dup[1..])?;
+                if let (Some(digest), true) = (digest, linked.files > 0 && !config.dry_run) {
+                    record_links(digester, digest, &dup);
+                }
+                savings = savings + linked;
+            }
+            Ok(savings)
+        })
+        .try_reduce(Savings::default, |a, b| Ok(a + b))?;
+    debug!("{} bytes saved", savings.freed);
+    debug!("{} files deduplicated", savings.files);
     info!(
         "{} saved in the deduplication of {} files",
-        pretty_bytes::converter::convert(dedup_size as f64),
-        dedup_files
+        pretty_bytes::converter::convert(savings.freed as f64),
+        savings.files
     );
-    Ok(())
+    if savings.shared_files > 0 {
+        info!(
+            "{} already shared by {} files",
+            pretty_bytes::converter::convert(savings.shared as f64),
+            savings.shared_files
+        );
+    }
+    let mut skipped: Vec<_> = savings.skipped.into_iter().collect();
+    skipped.sort();
+    for (skip, (size, files)) in skipped {
+        info!(
+            "{} skipped in {files} files {skip}",
+            pretty_bytes::converter::convert(size as f64),
+        );
+    }
+    if config.strategy.len() > 1 {
+        let mut strategies: Vec<_> = savings.strategies.into_iter().collect();
+        strategies.sort();
+        for (strategy, files) in strategies {
+            info!("{files} files {}", strategy.past());
+        }
+    }
+    let mut files = savings.files;
+    if let Some(min_size) = config.partial {
+        let paths = find_large_files(config, file_globs, cache_globs, min_size)?;
+        let (dedup_size, dedup_files) = partial::deduplicate(config, &paths)?;
+        info!(
+            "{} saved in the partial deduplication of {} files",
+            pretty_bytes::converter::convert(dedup_size as f64),
+            dedup_files
+        );
+        files += dedup_files;
+    }
+    Ok(files)
 }
 
-fn file_hardlinks(config: &Config, path: &Path, hardlinks: &[&PathBuf]) -> Result<u64> {
-    let metadata = fs::metadata(path).path_ctx(path)?;
+/// the space saved by the deduplication, in allocated bytes
+#[derive(Debug, Default)]
+struct Savings {
+    /// the space freed in this run
+    freed: u64,
+    files: usize,
+    /// the space already shared by the duplicates
+    shared: u64,
+    shared_files: usize,
+    /// the space and the number of the duplicates left as is, by reason
+    skipped: HashMap<Skip, (u64, usize)>,
+    /// the number of files linked with each strategy
+    strategies: HashMap<Strategy, usize>,
+}
+
+impl std::ops::Add for Savings {
+    type Output = Savings;
+
+    fn add(mut self, other: Savings) -> Savings {
+        self.freed += other.freed;
+        self.files += other.files;
+        self.shared += other.shared;
+        self.shared_files += other.shared_files;
+        for (skip, (size, files)) in other.skipped {
+            let skipped = self.skipped.entry(skip).or_default();
+            skipped.0 += size;
+            skipped.1 += files;
+        }
+        for (strategy, files) in other.strategies {
+            *self.strategies.entry(strategy).or_default() += files;
+        }
+        self
+    }
+}
+
+/// why a duplicate was left as is
+#[derive(Debug, Eq, PartialEq, Hash, Ord, PartialOrd, Copy, Clone, Display)]
+enum Skip {
+    #[strum(serialize = "on another device")]
+    CrossDevice,
+    #[strum(serialize = "not supported by the strategies")]
+    Unsupported,
+    #[strum(serialize = "with a different content")]
+    DifferentContent,
+    #[strum(serialize = "changed since they were hashed")]
+    Changed,
+}
+
+fn file_hardlinks(
+    config: &Config,
+    source: &Candidate,
+    candidates: &[&Candidate],
+) -> Result<Savings> {
+    let path = &source.path;
+    let metadata = fs::metadata(path).path_ctx(path);
+    let Some(metadata) = metadata.keep_going(config.keep_going, Step::Linking)? else {
+        return Ok(Savings::default());
+    };
     let inode = inos_m(&metadata);
-    for hardlink in hardlinks {
-        let hinode = inos(hardlink)?;
-        if hinode != inode && hinode.0 == inode.0 {
+    let mut savings = Savings::default();
+    // the links replaced so far and the initial number of links of each inode -
+    // its space is only freed once all its links are replaced
+    let mut replaced: HashMap<(u64, u64), (u64, u64)> = hashmap! {};
+    // the stamp of the source as left by the links made so far, and the inodes
+    // whose number of links was changed
+    let mut source_stamp = source.stamp;
+    let mut touched = HashSet::new();
+    let mut link_candidate = |candidate: &Candidate| -> Result<()> {
+        let hardlink = &candidate.path;
+        let hmetadata = fs::metadata(hardlink).path_ctx(hardlink)?;
+        let hinode = inos_m(&hmetadata);
+        let allocated = hmetadata.blocks() * 512;
+        if hinode == inode {
+            debug!(
+                "{} and {} are already linked",
+                path.display(),
+                hardlink.display(),
+            );
+            savings.shared += allocated;
+            savings.shared_files += 1;
+            return Ok(());
+        }
+        let shareable = config
+            .strategy
+            .iter()
+            .any(|s| matches!(s, Strategy::RefLink | Strategy::Dedupe));
+        if shareable && hinode.0 == inode.0 && share_extents(path, hardlink)? {
+            // nothing saved by sharing them again
             debug!(
-                "{}ing {} and {}",
-                config.strategy,
+                "{} and {} already share their extents",
+                path.display(),
+                hardlink.display(),
+            );
+            savings.shared += allocated;
+            savings.shared_files += 1;
+            return Ok(());
+        }
+        let linked = if config.verify && !same_content(path, hardlink)? {
+            warn!(
+                "{} and {} have the same digest but a different content",
                 path.display(),
                 hardlink.display(),
             );
-            let dest_metadata = fs::metadata(hardlink).path_ctx(hardlink)?;
-            if !config.dry_run {
-                std::fs::remove_file(hardlink).path_ctx(hardlink)?;
-                match config.strategy {
-                    Strategy::SymLink => ufs::symlink(path, hardlink).path_ctx(path)?,
-                    Strategy::HardLink => fs::hard_link(path, hardlink).path_ctx(path)?,
-                    Strategy::RefLink => reflink_copy::reflink(path, hardlink).path_ctx(path)?,
+            Err(Skip::DifferentContent)
+        } else if changed_since_hashed(path, &source_stamp, false)?
+            || changed_since_hashed(hardlink, &candidate.stamp, touched.contains(&hinode))?
+        {
+            Err(Skip::Changed)
+        } else {
+            link_file(config, path, hardlink, hinode.0 == inode.0)?
+        };
+        match linked {
+            Ok(strategy) => {
+                PROGRESS.link();
+                source_stamp = FileStamp::from(&fs::metadata(path).path_ctx(path)?);
+                touched.insert(hinode);
+                let (links, nlink) = replaced.entry(hinode).or_insert((0, hmetadata.nlink()));
+                *links += 1;
+                // the kernel deduplication frees the space in place
+                if strategy == Strategy::Dedupe || links == nlink {
+                    savings.freed += allocated;
                 }
-                restore_file_attributes(hardlink, &dest_metadata)?;
+                savings.files += 1;
+                *savings.strategies.entry(strategy).or_default() += 1;
+            }
+            Err(skip) => {
+                let skipped = savings.skipped.entry(skip).or_default();
+                skipped.0 += allocated;
+                skipped.1 += 1;
             }
+        }
+        Ok(())
+    };
+    for candidate in candidates {
+        signal::check()?;
+        link_candidate(candidate).keep_going(config.keep_going, Step::Linking)?;
+    }
+    Ok(savings)
+}
+
+/// record the stamp of the files linked to the first one, changed by their
+/// linking, so they are still found in the cache by the next run
+fn record_links(digester: &Digester, digest: Digest, dup: &[&Candidate]) {
+    let Ok(metadata) = fs::metadata(&dup[0].path) else {
+        return;
+    };
+    let inode = inos_m(&metadata);
+    for candidate in dup {
+        if let Ok(metadata) = fs::metadata(&candidate.path) {
+            if inos_m(&metadata) == inode {
+                digester.record(&candidate.path, FileStamp::from(&metadata), digest);
+            }
+        }
+    }
+}
+
+/// has the file changed or been replaced since it was hashed
+///
+/// The change time is not compared for the inodes whose number of links was
+/// changed by this run.
+fn changed_since_hashed(path: &Path, stamp: &FileStamp, ignore_ctime: bool) -> Result<bool> {
+    let current = FileStamp::from(&fs::metadata(path).path_ctx(path)?);
+    let changed = if ignore_ctime {
+        current.inode_key() != stamp.inode_key()
+    } else {
+        current != *stamp
+    };
+    if changed {
+        warn!(
+            "{} changed since it was hashed - skipping it",
+            path.display()
+        );
+    }
+    Ok(changed)
+}
+
+/// the outcome of a linking strategy
+enum Link {
+    Done,
+    /// the file was left untouched
+    Skipped(Skip),
+    /// the strategy can't be used for this file
+    Unsupported(io::Error),
+}
+
+/// replace a file by a link to another file with the same content, with the
+/// first strategy working for this file
+///
+/// Returns the strategy used, or the reason why the file was left untouched.
+fn link_file(
+    config: &Config,
+    path: &Path,
+    hardlink: &Path,
+    same_device: bool,
+) -> Result<std::result::Result<Strategy, Skip>> {
+    let mut strategies = config.strategy.iter().peekable();
+    while let Some(&strategy) = strategies.next() {
+        let link = if strategy != Strategy::SymLink && !same_device {
+            Link::Unsupported(io::Error::from_raw_os_error(libc::EXDEV))
         } else {
             debug!(
-                "{} and {} are already {}ed",
+                "{} {} and {}",
+                strategy.progressive(),
                 path.display(),
                 hardlink.display(),
-                config.strategy,
             );
+            if config.dry_run {
+                Link::Done
+            } else {
+                apply_strategy(strategy, path, hardlink)?
+            }
+        };
+        match link {
+            Link::Done => return Ok(Ok(strategy)),
+            Link::Skipped(skip) => return Ok(Err(skip)),
+            Link::Unsupported(e) => match strategies.peek() {
+                Some(next) => debug!("{}: {e} - falling back to {next}", hardlink.display()),
+                // the files on different devices can't be linked, and are not worth a warning
+                None if e.raw_os_error() == Some(libc::EXDEV) => {
+                    debug!("{}: {e}", hardlink.display());
+                    return Ok(Err(Skip::CrossDevice));
+                }
+                None => {
+                    warn!("{}: {e}", hardlink.display());
+                    return Ok(Err(Skip::Unsupported));
+                }
+            },
+        }
+    }
+    Ok(Err(Skip::Unsupported))
+}
+
+/// replace a file by a link to another file with a single strategy
+///
+/// The link is created next to the file, and then renamed over it, so the file
+/// is left untouched if the strategy can't be used.
+fn apply_strategy(strategy: Strategy, path: &Path, hardlink: &Path) -> Result<Link> {
+    if strategy == Strategy::Dedupe {
+        // the kernel only shares the identical ranges, in place
+        return match dedupe(path, hardlink)? {
+            Dedupe::Done => Ok(Link::Done),
+            Dedupe::Differs => {
+                warn!(
+                    "{} and {} have the same digest but a different content",
+                    path.display(),
+                    hardlink.display(),
+                );
+                Ok(Link::Skipped(Skip::DifferentContent))
+            }
+            Dedupe::Unsupported => Ok(Link::Unsupported(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "the kernel deduplication is not supported by the filesystem",
+            ))),
+        };
+    }
+    let dest_metadata = fs::metadata(hardlink).path_ctx(hardlink)?;
+    let tmp_path = tmp_link_path(hardlink);
+    let res = match strategy {
+        Strategy::SymLink => ufs::symlink(path, &tmp_path),
+        Strategy::HardLink => fs::hard_link(path, &tmp_path),
+        Strategy::RefLink => reflink_copy::reflink(path, &tmp_path),
+        Strategy::Dedupe => unreachable!(),
+    };
+    let link = (|| {
+        match res {
+            Err(e) if is_unsupported(&e) => return Ok(Link::Unsupported(e)),
+            res => res.path_ctx(path)?,
         }
+        fs::rename(&tmp_path, hardlink).path_ctx(hardlink)?;
+        restore_file_attributes(hardlink, &dest_metadata)?;
+        Ok(Link::Done)
+    })();
+    if !matches!(link, Ok(Link::Done)) {
+        // don't leave a partial copy or a link behind
+        let _ = fs::remove_file(&tmp_path);
     }
-    Ok(metadata.len() * hardlinks.len() as u64)
+    link
+}
+
+/// is this error telling that a strategy can't be used for a file
+fn is_unsupported(err: &io::Error) -> bool {
+    matches!(
+        err.raw_os_error(),
+        Some(
+            libc::EXDEV
+                | libc::EMLINK
+                | libc::EOPNOTSUPP
+                | libc::ENOTTY
+                | libc::EINVAL
+                | libc::EPERM
+        )
+    )
+}
+
+/// compare the content of two files byte by byte
+fn same_content(path1: &Path, path2: &Path) -> Result<bool> {
+    debug!("verifying {} and {}", path1.display(), path2.display());
+    let mut reader1 = io::BufReader::new(File::open(path1).path_ctx(path1)?);
+    let mut reader2 = io::BufReader::new(File::open(path2).path_ctx(path2)?);
+    loop {
+        let buf1 = reader1.fill_buf().path_ctx(path1)?;
+        let buf2 = reader2.fill_buf().path_ctx(path2)?;
+        let len = buf1.len().min(buf2.len());
+        if buf1[..len] != buf2[..len] {
+            return Ok(false);
+        }
+        if len == 0 {
+            return Ok(buf1.is_empty() && buf2.is_empty());
+        }
+        reader1.consume(len);
+        reader2.consume(len);
+    }
+}
+
+/// replace the symbolic links with the same target by hardlinks of a single symbolic link
+///
+/// Returns the number of symbolic links deduplicated.
+pub fn symlink_deduplicate(config: &Config, paths: &[PathBuf]) -> Result<usize> {
+    let mut groups = hashmap! {};
+    for path in paths {
+        let res = path
+            .symlink_metadata()
+            .and_then(|metadata| Ok((metadata, fs::read_link(path)?)))
+            .path_ctx(path);
+        let Some((metadata, target)) = res.keep_going(config.keep_going, Step::Discovery)? else {
+            continue;
+        };
+        groups
+            .entry((metadata.dev(), target))
+            .or_insert_with(Vec::new)
+            .push((path, metadata));
+    }
+    let mut dedup_files: usize = 0;
+    for (_, group) in groups.into_iter().filter(|(_, v)| v.len() >= 2) {
+        let (path, metadata) = &group[0];
+        for (symlink, smetadata) in &group[1..] {
+            signal::check()?;
+            if smetadata.ino() != metadata.ino() {
+                debug!(
+                    "hardlinking symlinks {} and {}",
+                    path.display(),
+                    symlink.display()
+                );
+                let res = if config.dry_run {
+                    Ok(())
+                } else {
+                    hardlink_symlink(path, symlink)
+                };
+                if res.keep_going(config.keep_going, Step::Linking)?.is_some() {
+                    dedup_files += 1;
+                }
+            } else {
+                debug!(
+                    "symlinks {} and {} are already hardlinked",
+                    path.display(),
+                    symlink.display()
+                );
+            }
+        }
+    }
+    info!("{dedup_files} symlinks deduplicated");
+    Ok(dedup_files)
+}
+
+/// replace a symlink by a hard link to another symlink
+///
+/// The link is created next to the symlink, and then renamed over it, so the
+/// symlink is never missing.
+fn hardlink_symlink(path: &Path, symlink: &Path) -> Result<()> {
+    let tmp_path = tmp_link_path(symlink);
+    fs::hard_link(path, &tmp_path).path_ctx(path)?;
+    fs::rename(&tmp_path, symlink).or_else(|e| {
+        let _ = fs::remove_file(&tmp_path);
+        Err(e).path_ctx(symlink)
+    })
+}
+
+/// the path of the temporary link created next to a file before replacing it
+fn tmp_link_path(path: &Path) -> PathBuf {
+    let mut tmp_name = OsString::from(".");
+    tmp_name.push(path.file_name().unwrap_or_default());
+    tmp_name.push(format!(".hld.{}", std::process::id()));
+    path.with_file_name(tmp_name)
 }
 
 fn restore_file_attributes(path: &Path, metadata: &fs::Metadata) -> Result<()> {
@@ -209,36 +843,180 @@ fn restore_file_attributes(path: &Path, metadata: &fs::Metadata) -> Result<()> {
     Ok(())
 }
 
-pub fn glob_to_files(globs: &[String]) -> Result<Vec<PathBuf>> {
-    let res = globs
-        .par_iter()
-        .map(|glob| {
-            let mut res = VecDeque::new();
-            for path in glob::glob(glob).glob_ctx(glob)? {
-                let path = path?;
-                if path
-                    .symlink_metadata()
-                    .path_ctx(&path)?
-                    .file_type()
-                    .is_file()
-                {
-                    res.push_back(path);
-                }
-            }
-            Ok(res)
-        })
-        .collect::<Result<Vec<VecDeque<PathBuf>>>>()?;
-    let mut res: Vec<PathBuf> = res.iter().flatten().cloned().collect();
+/// is the file old enough to be processed - neither its content nor its inode
+/// were modified for at least the duration given with `--min-age`
+fn old_enough(config: &Config, metadata: &fs::Metadata) -> bool {
+    let Some(limit) = config
+        .min_age
+        .and_then(|min_age| SystemTime::now().checked_sub(min_age))
+    else {
+        return config.min_age.is_none();
+    };
+    let ctime = UNIX_EPOCH + Duration::new(metadata.ctime() as u64, metadata.ctime_nsec() as u32);
+    metadata.modified().is_ok_and(|mtime| mtime <= limit) && ctime <= limit
+}
+
+/// select the regular files
+///
+/// The symbolic links are ignored, unless `follow_symlinks` is set. In that case
+/// the real path of the file they point to is returned instead.
+fn select_file(path: PathBuf, follow_symlinks: bool, report: bool) -> Result<Option<PathBuf>> {
+    let file_type = path.symlink_metadata().path_ctx(&path)?.file_type();
+    if file_type.is_file() {
+        Ok(Some(path))
+    } else if file_type.is_symlink() && follow_symlinks {
+        resolve_symlink(&path, report)
+    } else {
+        Ok(None)
+    }
+}
+
+/// find the symbolic links matching the globs
+pub fn glob_to_symlinks(globs: &[String], excludes: &[String]) -> Result<Vec<PathBuf>> {
+    let mut res = walk_globs(globs, &[], excludes, |path| {
+        let file_type = path.symlink_metadata().path_ctx(&path)?.file_type();
+        Ok(file_type.is_symlink().then_some(path))
+    })
+    .into_iter()
+    .map(|res| res.map(|(path, _)| path))
+    .collect::<Result<Vec<PathBuf>>>()?;
     res.par_sort();
     res.dedup();
     Ok(res)
 }
 
-/// returns the inodes of the partition and of the file
-fn inos(path: &Path) -> Result<(u64, u64)> {
-    Ok(inos_m(&fs::metadata(path).path_ctx(path)?))
+/// walk the globs in a background thread and stream the selected paths through
+/// a bounded channel
+///
+/// Each path comes with a flag telling whether it was matched by a cache glob.
+/// The paths matched by an excluded glob are skipped. The errors on a single
+/// path are sent along with the paths, and the walk goes on with the next ones.
+/// The walk stops early when the receiver is dropped.
+fn walk_globs<F>(
+    file_globs: &[String],
+    cache_globs: &[String],
+    excludes: &[String],
+    select: F,
+) -> Receiver<Result<(PathBuf, bool)>>
+where
+    F: Fn(PathBuf) -> Result<Option<PathBuf>> + Send + 'static,
+{
+    let globs: Vec<(String, bool)> = chain(
+        cache_globs.iter().map(|glob| (glob.clone(), true)),
+        file_globs.iter().map(|glob| (glob.clone(), false)),
+    )
+    .collect();
+    let excludes = excludes.to_vec();
+    let (sender, receiver) = mpsc::sync_channel(CHANNEL_BOUND);
+    thread::spawn(move || {
+        let walk = || -> Result<()> {
+            let excludes = excludes
+                .iter()
+                .map(|glob| glob::Pattern::new(glob).glob_ctx(glob))
+                .collect::<Result<Vec<_>>>()?;
+            for (glob, cached) in &globs {
+                for path in glob::glob(glob).glob_ctx(glob)? {
+                    signal::check()?;
+                    let selected = path.map_err(Error::from).and_then(|path| {
+                        if excludes.iter().any(|exclude| exclude.matches_path(&path)) {
+                            trace!("excluding {}", path.display());
+                            return Ok(None);
+                        }
+                        select(path)
+                    });
+                    let res = match selected {
+                        Ok(Some(path)) => Ok((path, *cached)),
+                        Ok(None) => continue,
+                        Err(e) => Err(e),
+                    };
+                    if sender.send(res).is_err() {
+                        // nobody is listening anymore
+                        return Ok(());
+                    }
+                }
+            }
+            Ok(())
+        };
+        if let Err(e) = walk() {
+            let _ = sender.send(Err(e));
+        }
+    });
+    receiver
 }
 
+/// returns the real path of the regular file pointed by a symbolic link
+///
+/// The dangling links, the links to something else than a regular file and the
+/// symbolic link loops are skipped, and reported if `report` is set.
+fn resolve_symlink(path: &Path, report: bool) -> Result<Option<PathBuf>> {
+    let real_path = match fs::canonicalize(path) {
+        Ok(real_path) => real_path,
+        Err(e) if e.raw_os_error() == Some(libc::ELOOP) => {
+            if report {
+                warn!("{}: symbolic link loop detected", path.display());
+            }
+            return Ok(None);
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            if report {
+                warn!("{}: dangling symbolic link", path.display());
+            }
+            return Ok(None);
+        }
+        Err(e) => return Err(e).path_ctx(path),
+    };
+    if !fs::metadata(&real_path)
+        .path_ctx(&real_path)?
+        .file_type()
+        .is_file()
+    {
+        return Ok(None);
+    }
+    if report {
+        debug!("following {} to {}", path.display(), real_path.display());
+    }
+    Ok(Some(real_path))
+}
+
+/// returns the inodes of the partition and of the file
 fn inos_m(metadata: &fs::Metadata) -> (u64, u64) {
     (metadata.dev(), metadata.ino())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use assert_fs::prelude::*;
+    use clap::Parser;
+
+    #[test]
+    fn skip_changed_files() {
+        let tmp = assert_fs::TempDir::new().unwrap();
+        let foo = tmp.child("foo");
+        let bar = tmp.child("bar");
+        foo.write_binary(&[1; 100_000]).unwrap();
+        bar.write_binary(&[1; 100_000]).unwrap();
+        let candidate = |path: &Path| {
+            let metadata = fs::metadata(path).unwrap();
+            Candidate {
+                path: path.to_path_buf(),
+                stamp: FileStamp::from(&metadata),
+                allocated: metadata.blocks() * 512,
+                cached: false,
+            }
+        };
+        let source = candidate(foo.path());
+        let changed = candidate(bar.path());
+        // bar is modified between its hashing and its linking
+        let mtime = filetime::FileTime::from_unix_time(1_000_000_000, 0);
+        filetime::set_file_mtime(bar.path(), mtime).unwrap();
+
+        let config = Config::parse_from(["hld"]);
+        let savings = file_hardlinks(&config, &source, &[&changed]).unwrap();
+        assert_eq!(savings.files, 0);
+        assert_eq!(savings.freed, 0);
+        assert_eq!(savings.skipped[&Skip::Changed], (changed.allocated, 1));
+        let ino = |path: &Path| fs::metadata(path).unwrap().ino();
+        assert_ne!(ino(foo.path()), ino(bar.path()));
+    }
+}
@@ -0,0 +1,52 @@
+use std::collections::HashMap;
+use std::num::NonZeroUsize;
+use std::sync::{Condvar, Mutex};
+
+/// limit the number of concurrent reads on each device
+pub struct DeviceLimiter {
+    limit: Option<NonZeroUsize>,
+    active: Mutex<HashMap<u64, usize>>,
+    released: Condvar,
+}
+
+/// a permit to read on a device, released when dropped
+pub struct DevicePermit<'a> {
+    limiter: &'a DeviceLimiter,
+    dev: u64,
+}
+
+impl DeviceLimiter {
+    /// create a limiter allowing `limit` concurrent reads per device, or an
+    /// unlimited number if `None`
+    pub fn new(limit: Option<NonZeroUsize>) -> DeviceLimiter {
+        DeviceLimiter {
+            limit,
+            active: Mutex::new(HashMap::new()),
+            released: Condvar::new(),
+        }
+    }
+
+    /// wait until a read is allowed on the device
+    pub fn acquire(&self, dev: u64) -> Option<DevicePermit<'_>> {
+        let limit = self.limit?.get();
+        let mut active = self.active.lock().unwrap();
+        while active.get(&dev).copied().unwrap_or(0) >= limit {
+            active = self.released.wait(active).unwrap();
+        }
+        *active.entry(dev).or_insert(0) += 1;
+        Some(DevicePermit { limiter: self, dev })
+    }
+}
+
+impl Drop for DevicePermit<'_> {
+    fn drop(&mut self) {
+        let mut active = self.limiter.active.lock().unwrap();
+        if let Some(count) = active.get_mut(&self.dev) {
+            *count -= 1;
+            if *count == 0 {
+                active.remove(&self.dev);
+            }
+        }
+        self.limiter.released.notify_all();
+    }
+}
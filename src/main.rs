@@ -3,32 +3,50 @@ extern crate log;
 #[macro_use]
 extern crate maplit;
 
+mod cache;
 mod cli;
+mod digest;
 mod error;
+mod extent;
 mod hld;
+mod limiter;
+mod partial;
+mod profile;
+mod progress;
+mod signal;
+mod stamp;
 mod strategy;
 
+use error::ExitCode;
 use std::io;
 
-use clap::{CommandFactory, Parser};
+use clap::{CommandFactory, FromArgMatches};
 use clap_complete::generate;
 
-fn run() -> error::Result<()> {
-    let args = cli::Config::parse();
-    ocli::init(args.log_level.to_owned().into())?;
-
+fn run() -> error::Result<ExitCode> {
+    let matches = cli::Config::command().get_matches();
+    let mut args = cli::Config::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    progress::init_logger(args.log_level.to_owned().into())?;
     if let Some(shell) = args.completion {
         generate(shell, &mut cli::Config::command(), "hld", &mut io::stdout());
         std::process::exit(0);
     }
 
+    profile::apply(&mut args, &matches)?;
+
     if let Some(parallel) = args.parallel {
         debug!("using {parallel} threads at most");
+    }
+    if let Some(io_threads) = args.io_threads.or(args.parallel) {
+        debug!("using {io_threads} I/O threads at most");
         rayon::ThreadPoolBuilder::new()
-            .num_threads(parallel)
+            .num_threads(io_threads)
             .build_global()?;
     }
 
+    signal::install()?;
+    let _reporter = args.progress.then(progress::Reporter::start);
+
     let file_globs = if args.recursive {
         args.files.iter().map(|d| format!("{d}/**/*")).collect()
     } else {
@@ -39,17 +57,33 @@ fn run() -> error::Result<()> {
     } else {
         args.caches.clone()
     };
-    let files = hld::glob_to_files(&file_globs)?;
-    let caches = hld::glob_to_files(&cache_globs)?;
-    trace!("files: {files:?}");
-    trace!("caches: {caches:?}");
-    hld::hardlink_deduplicate(&args, &files, &caches)?;
-    Ok(())
+    trace!("file globs: {file_globs:?}");
+    trace!("cache globs: {cache_globs:?}");
+    if args.print_digests {
+        hld::print_digests(&args, &file_globs, &cache_globs)?;
+        return Ok(ExitCode::Success);
+    }
+    let mut files = hld::hardlink_deduplicate(&args, &file_globs, &cache_globs)?;
+    if args.dedup_symlinks {
+        let symlinks = hld::glob_to_symlinks(&file_globs, &args.excludes)?;
+        trace!("symlinks: {symlinks:?}");
+        files += hld::symlink_deduplicate(&args, &symlinks)?;
+    }
+    if files == 0 && args.detailed_exit_codes {
+        Ok(ExitCode::NothingToDo)
+    } else {
+        Ok(ExitCode::Success)
+    }
 }
 
 fn main() {
-    if let Err(err) = run() {
+    let res = run().and_then(|code| match error::report_failures() {
+        0 => Ok(code),
+        failures => Err(error::Error::Incomplete(failures)),
+    });
+    let code = res.unwrap_or_else(|err| {
         error!("{err}");
-        std::process::exit(1);
-    }
+        err.exit_code()
+    });
+    std::process::exit(code as i32);
 }
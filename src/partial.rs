@@ -0,0 +1,186 @@
+use crate::cli::Config;
+use crate::error::{IOResultExt, KeepGoingExt, Result, Step};
+use crate::extent::{
+    data_segments, dedupe_range, extents, open_dest, physical_offset, Dedupe, Extent,
+};
+use crate::limiter::DeviceLimiter;
+use crate::signal;
+use rayon::prelude::*;
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+/// the size of the blocks compared in the partial deduplication
+const BLOCK_SIZE: u64 = 1024 * 1024;
+
+/// a large file, split in blocks
+struct BlockFile {
+    path: PathBuf,
+    /// the digest and the offset of its blocks, except the zero filled ones
+    blocks: Vec<(blake3::Hash, u64)>,
+    extents: Option<Vec<Extent>>,
+}
+
+/// share the identical blocks of the files with the kernel deduplication
+///
+/// Returns the number of bytes saved, and the number of files changed.
+pub fn deduplicate(config: &Config, paths: &[PathBuf]) -> Result<(u64, usize)> {
+    // only process each inode once
+    let mut inodes = hashmap! {};
+    for path in paths {
+        let metadata = path.metadata().path_ctx(path);
+        let Some(metadata) = metadata.keep_going(config.keep_going, Step::Discovery)? else {
+            continue;
+        };
+        inodes
+            .entry((metadata.dev(), metadata.ino()))
+            .or_insert(path);
+    }
+    let mut paths: Vec<&PathBuf> = inodes.into_values().collect();
+    paths.sort();
+    debug!(
+        "{} candidate files for the partial deduplication",
+        paths.len()
+    );
+
+    let limiter = DeviceLimiter::new(config.io_threads_per_device);
+    let files = paths
+        .into_par_iter()
+        .filter_map(|path| {
+            let file = (|| {
+                signal::check()?;
+                debug!("computing the block digests of {}", path.display());
+                let blocks = block_digests(path, &limiter)?;
+                let extents = extents(path)?;
+                Ok(BlockFile {
+                    path: path.clone(),
+                    blocks,
+                    extents,
+                })
+            })();
+            file.keep_going(config.keep_going, Step::Hashing)
+                .transpose()
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    // group the identical blocks, the first one being the source of the others
+    let mut sources: HashMap<blake3::Hash, (usize, u64)> = hashmap! {};
+    let mut ranges: HashMap<usize, Vec<(usize, u64, u64)>> = hashmap! {};
+    for (index, file) in files.iter().enumerate() {
+        for (digest, offset) in &file.blocks {
+            match sources.get(digest) {
+                Some(&(src, src_offset)) => ranges
+                    .entry(index)
+                    .or_default()
+                    .push((src, src_offset, *offset)),
+                None => {
+                    sources.insert(*digest, (index, *offset));
+                }
+            }
+        }
+    }
+    drop(sources);
+
+    let mut dedup_size = 0;
+    let mut dedup_files = 0;
+    let mut ranges: Vec<_> = ranges.into_iter().collect();
+    ranges.sort();
+    for (dest, ranges) in ranges {
+        signal::check()?;
+        let dest = &files[dest];
+        let shared = share_blocks(config, &files, dest, ranges);
+        let Some(shared) = shared.keep_going(config.keep_going, Step::Linking)? else {
+            continue;
+        };
+        if shared > 0 {
+            debug!(
+                "sharing {shared} blocks of {} with the other files",
+                dest.path.display()
+            );
+            dedup_size += shared * BLOCK_SIZE;
+            dedup_files += 1;
+        }
+    }
+    Ok((dedup_size, dedup_files))
+}
+
+/// share the blocks of a file with the identical blocks of the other files
+///
+/// Returns the number of blocks shared.
+fn share_blocks(
+    config: &Config,
+    files: &[BlockFile],
+    dest: &BlockFile,
+    ranges: Vec<(usize, u64, u64)>,
+) -> Result<u64> {
+    let dest_file = open_dest(&dest.path)?;
+    let mut src_files = hashmap! {};
+    let mut shared = 0;
+    for (src, src_offset, dest_offset) in ranges {
+        let src = &files[src];
+        if already_shared(src, src_offset, dest, dest_offset) {
+            continue;
+        }
+        if !config.dry_run {
+            let src_file = match src_files.entry(&src.path) {
+                Entry::Occupied(entry) => entry.into_mut(),
+                Entry::Vacant(entry) => entry.insert(File::open(&src.path).path_ctx(&src.path)?),
+            };
+            match dedupe_range(src_file, src_offset, &dest_file, dest_offset, BLOCK_SIZE)
+                .path_ctx(&dest.path)?
+            {
+                Dedupe::Done => (),
+                Dedupe::Differs => continue,
+                Dedupe::Unsupported => {
+                    warn!(
+                        "{}: the kernel deduplication is not supported by the filesystem",
+                        dest.path.display(),
+                    );
+                    break;
+                }
+            }
+        }
+        shared += 1;
+    }
+    Ok(shared)
+}
+
+/// compute the digests of the full blocks of a file, skipping the zero filled ones
+///
+/// Only the blocks overlapping a data segment are read: the others are holes.
+fn block_digests(path: &Path, limiter: &DeviceLimiter) -> Result<Vec<(blake3::Hash, u64)>> {
+    let mut file = File::open(path).path_ctx(path)?;
+    let metadata = file.metadata().path_ctx(path)?;
+    let _permit = limiter.acquire(metadata.dev());
+    let len = metadata.len();
+    let mut buffer = vec![0; BLOCK_SIZE as usize];
+    let mut blocks = vec![];
+    let mut offset = 0;
+    for (start, end) in data_segments(&file, len).path_ctx(path)? {
+        // a block may overlap several segments, but is only read once
+        offset = offset.max(start / BLOCK_SIZE * BLOCK_SIZE);
+        if offset < end {
+            file.seek(SeekFrom::Start(offset)).path_ctx(path)?;
+        }
+        while offset < end && offset + BLOCK_SIZE <= len {
+            file.read_exact(&mut buffer).path_ctx(path)?;
+            if buffer.iter().any(|b| *b != 0) {
+                blocks.push((blake3::hash(&buffer), offset));
+            }
+            offset += BLOCK_SIZE;
+        }
+    }
+    Ok(blocks)
+}
+
+/// are the two blocks already stored in the same extent
+fn already_shared(src: &BlockFile, src_offset: u64, dest: &BlockFile, dest_offset: u64) -> bool {
+    let (Some(src_extents), Some(dest_extents)) = (&src.extents, &dest.extents) else {
+        return false;
+    };
+    let src_physical = physical_offset(src_extents, src_offset, BLOCK_SIZE);
+    src_physical.is_some() && src_physical == physical_offset(dest_extents, dest_offset, BLOCK_SIZE)
+}
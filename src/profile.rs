@@ -0,0 +1,121 @@
+use crate::cli::Config;
+use crate::error::{Error, IOResultExt, Result};
+use crate::strategy::Strategy;
+use clap::parser::ValueSource;
+use clap::ArgMatches;
+use directories::{BaseDirs, ProjectDirs};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::num::NonZeroUsize;
+use std::path::PathBuf;
+
+/// the profile used when none is given on the command line
+const DEFAULT_PROFILE: &str = "default";
+
+/// the content of the configuration file
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    #[serde(default)]
+    profile: HashMap<String, Profile>,
+}
+
+/// a named set of options
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+struct Profile {
+    files: Vec<String>,
+    caches: Vec<String>,
+    excludes: Vec<String>,
+    strategy: Option<Vec<Strategy>>,
+    cache_path: Option<String>,
+    recursive: Option<bool>,
+    parallel: Option<usize>,
+    io_threads: Option<usize>,
+    hash_threads: Option<usize>,
+    io_threads_per_device: Option<NonZeroUsize>,
+}
+
+pub fn default_config_path() -> PathBuf {
+    let mut path = ProjectDirs::from("com", "glehmann", "hld")
+        .unwrap()
+        .config_dir()
+        .to_path_buf();
+    path.push("config.toml");
+    path
+}
+
+/// apply the options of the selected profile, except the ones given on the
+/// command line or in the environment
+pub fn apply(config: &mut Config, matches: &ArgMatches) -> Result<()> {
+    let explicit_path = config.config.is_some();
+    let path = config.config.clone().unwrap_or_else(default_config_path);
+    let content = match fs::read_to_string(&path) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound && !explicit_path => String::new(),
+        res => res.path_ctx(&path)?,
+    };
+    let mut file: ConfigFile = toml::from_str(&content).map_err(|source| Error::ConfigFile {
+        source,
+        path: path.clone(),
+    })?;
+    let name = config.profile.as_deref().unwrap_or(DEFAULT_PROFILE);
+    let profile = match file.profile.remove(name) {
+        Some(profile) => profile,
+        None if config.profile.is_none() => return Ok(()),
+        None => {
+            return Err(Error::UnknownProfile {
+                name: name.to_owned(),
+                path,
+            })
+        }
+    };
+    debug!("using the profile {name} of {}", path.display());
+
+    let unset = |id: &str| {
+        !matches!(
+            matches.value_source(id),
+            Some(ValueSource::CommandLine | ValueSource::EnvVariable)
+        )
+    };
+    if unset("FILE") && !profile.files.is_empty() {
+        config.files = profile.files.iter().map(|f| expand_home(f)).collect();
+    }
+    if unset("caches") && !profile.caches.is_empty() {
+        config.caches = profile.caches.iter().map(|c| expand_home(c)).collect();
+    }
+    if unset("excludes") && !profile.excludes.is_empty() {
+        config.excludes = profile.excludes.iter().map(|e| expand_home(e)).collect();
+    }
+    if let (true, Some(strategy)) = (unset("strategy"), profile.strategy) {
+        config.strategy = strategy;
+    }
+    if let (true, Some(cache_path)) = (unset("cache_path"), profile.cache_path) {
+        config.cache_path = PathBuf::from(expand_home(&cache_path));
+    }
+    if let (true, Some(recursive)) = (unset("recursive"), profile.recursive) {
+        config.recursive = recursive;
+    }
+    if unset("parallel") && profile.parallel.is_some() {
+        config.parallel = profile.parallel;
+    }
+    if unset("io_threads") && profile.io_threads.is_some() {
+        config.io_threads = profile.io_threads;
+    }
+    if unset("hash_threads") && profile.hash_threads.is_some() {
+        config.hash_threads = profile.hash_threads;
+    }
+    if unset("io_threads_per_device") && profile.io_threads_per_device.is_some() {
+        config.io_threads_per_device = profile.io_threads_per_device;
+    }
+    Ok(())
+}
+
+/// replace the leading `~` of a path by the home directory
+fn expand_home(path: &str) -> String {
+    match (path.strip_prefix("~/"), BaseDirs::new()) {
+        (Some(rest), Some(dirs)) => dirs.home_dir().join(rest).display().to_string(),
+        _ => path.to_owned(),
+    }
+}
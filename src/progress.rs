@@ -0,0 +1,197 @@
+use std::io::{self, IsTerminal, Write};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// the refresh interval of the progress line on a terminal
+const TTY_INTERVAL: Duration = Duration::from_millis(200);
+
+/// the interval between two progress log lines when stderr is not a terminal
+const LOG_INTERVAL: Duration = Duration::from_secs(10);
+
+/// the progress line is displayed on the terminal, and must be cleared before
+/// logging anything
+static LINE_SHOWN: AtomicBool = AtomicBool::new(false);
+
+/// a logger clearing the progress line before each record, so the record is not
+/// appended to it
+pub struct Logger(ocli::Logger);
+
+impl log::Log for Logger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        self.0.enabled(metadata)
+    }
+
+    fn log(&self, record: &log::Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        // the lock is held until the record is written, so the progress line is
+        // not displayed again in the meantime
+        let mut stderr = io::stderr().lock();
+        if LINE_SHOWN.swap(false, Ordering::Relaxed) {
+            let _ = write!(stderr, "\r\x1b[2K");
+        }
+        self.0.log(record);
+    }
+
+    fn flush(&self) {
+        self.0.flush();
+    }
+}
+
+/// initialize the logger
+pub fn init_logger(level: log::Level) -> Result<(), log::SetLoggerError> {
+    log::set_max_level(level.to_level_filter());
+    log::set_boxed_logger(Box::new(Logger(ocli::Logger::new().level(level))))
+}
+
+/// the progress counters, shared by all the threads
+pub struct Progress {
+    discovered: AtomicU64,
+    stated: AtomicU64,
+    hashed_bytes: AtomicU64,
+    total_bytes: AtomicU64,
+    groups: AtomicU64,
+    links: AtomicU64,
+}
+
+pub static PROGRESS: Progress = Progress::new();
+
+impl Progress {
+    const fn new() -> Progress {
+        Progress {
+            discovered: AtomicU64::new(0),
+            stated: AtomicU64::new(0),
+            hashed_bytes: AtomicU64::new(0),
+            total_bytes: AtomicU64::new(0),
+            groups: AtomicU64::new(0),
+            links: AtomicU64::new(0),
+        }
+    }
+
+    /// a file has been found while walking the globs
+    pub fn discovered(&self) {
+        self.discovered.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// a file has been stat'ed to decide if it is a candidate for the deduplication
+    pub fn stated(&self) {
+        self.stated.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// some bytes are going to be hashed
+    pub fn to_hash(&self, bytes: u64) {
+        self.total_bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// some bytes have been hashed
+    pub fn hashed(&self, bytes: u64) {
+        self.hashed_bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// a group of duplicated files has been found
+    pub fn group(&self) {
+        self.groups.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// a file has been linked
+    pub fn link(&self) {
+        self.links.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn line(&self, hash_start: Option<Instant>) -> String {
+        let discovered = self.discovered.load(Ordering::Relaxed);
+        let hashed_bytes = self.hashed_bytes.load(Ordering::Relaxed);
+        let total_bytes = self.total_bytes.load(Ordering::Relaxed);
+        let mut line = format!(
+            "{discovered} files found, {}/{discovered} stat'ed, {}/{} hashed, {} duplicate groups, {} links",
+            self.stated.load(Ordering::Relaxed),
+            pretty_bytes::converter::convert(hashed_bytes as f64),
+            pretty_bytes::converter::convert(total_bytes as f64),
+            self.groups.load(Ordering::Relaxed),
+            self.links.load(Ordering::Relaxed),
+        );
+        if let Some(hash_start) = hash_start {
+            let elapsed = hash_start.elapsed().as_secs_f64();
+            if hashed_bytes > 0 && elapsed > 0.0 {
+                let rate = hashed_bytes as f64 / elapsed;
+                let eta = (total_bytes.saturating_sub(hashed_bytes)) as f64 / rate;
+                line.push_str(&format!(", ETA {}", format_duration(eta as u64)));
+            }
+        }
+        line
+    }
+}
+
+/// display the progress on stderr until dropped
+pub struct Reporter {
+    stop: Arc<AtomicBool>,
+    handle: Option<thread::JoinHandle<()>>,
+}
+
+impl Reporter {
+    /// start displaying the progress, in place on a terminal, or with periodic log
+    /// lines otherwise
+    pub fn start() -> Reporter {
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread_stop = stop.clone();
+        let handle = thread::spawn(move || {
+            let tty = io::stderr().is_terminal();
+            let interval = if tty { TTY_INTERVAL } else { LOG_INTERVAL };
+            let mut hash_start = None;
+            let mut last_report = Instant::now();
+            while !thread_stop.load(Ordering::Relaxed) {
+                thread::park_timeout(TTY_INTERVAL);
+                if hash_start.is_none() && PROGRESS.total_bytes.load(Ordering::Relaxed) > 0 {
+                    hash_start = Some(Instant::now());
+                }
+                if last_report.elapsed() < interval || thread_stop.load(Ordering::Relaxed) {
+                    continue;
+                }
+                last_report = Instant::now();
+                if tty {
+                    let mut stderr = io::stderr().lock();
+                    let _ = write!(stderr, "\r\x1b[2K{}", PROGRESS.line(hash_start));
+                    let _ = stderr.flush();
+                    LINE_SHOWN.store(true, Ordering::Relaxed);
+                } else {
+                    info!("{}", PROGRESS.line(hash_start));
+                }
+            }
+            if tty {
+                let _ = write!(io::stderr(), "\r\x1b[2K");
+                LINE_SHOWN.store(false, Ordering::Relaxed);
+            } else {
+                debug!("{}", PROGRESS.line(hash_start));
+            }
+        });
+        Reporter {
+            stop,
+            handle: Some(handle),
+        }
+    }
+}
+
+impl Drop for Reporter {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(handle) = self.handle.take() {
+            handle.thread().unpark();
+            let _ = handle.join();
+        }
+    }
+}
+
+/// format a duration in seconds like 1h02m03s
+fn format_duration(secs: u64) -> String {
+    let (hours, minutes, seconds) = (secs / 3600, secs / 60 % 60, secs % 60);
+    if hours > 0 {
+        format!("{hours}h{minutes:02}m{seconds:02}s")
+    } else if minutes > 0 {
+        format!("{minutes}m{seconds:02}s")
+    } else {
+        format!("{seconds}s")
+    }
+}
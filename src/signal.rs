@@ -0,0 +1,28 @@
+use crate::error::{Error, ExitCode, Result};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// catch SIGINT and SIGTERM, so the operations in progress can be completed
+/// before exiting
+///
+/// A second signal exits immediately, for the runs blocked in an operation.
+pub fn install() -> Result<()> {
+    ctrlc::set_handler(|| {
+        if INTERRUPTED.swap(true, Ordering::SeqCst) {
+            error!("interrupted again - exiting now");
+            std::process::exit(ExitCode::Interrupted as i32);
+        }
+        warn!("interrupted - finishing the operations in progress");
+    })?;
+    Ok(())
+}
+
+/// fail if an interruption has been requested
+pub fn check() -> Result<()> {
+    if INTERRUPTED.load(Ordering::SeqCst) {
+        Err(Error::Interrupted)
+    } else {
+        Ok(())
+    }
+}
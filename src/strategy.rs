@@ -1,12 +1,35 @@
 use clap::ValueEnum;
+use serde::Deserialize;
 use strum::Display;
 
-#[derive(Debug, Eq, PartialEq, Copy, ValueEnum, Clone, Display)]
+#[derive(
+    Debug, Eq, PartialEq, Hash, Ord, PartialOrd, Copy, ValueEnum, Clone, Display, Deserialize,
+)]
 #[value(rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
 #[strum(serialize_all = "lowercase")]
 #[allow(clippy::enum_variant_names)]
 pub enum Strategy {
     HardLink,
     SymLink,
     RefLink,
+    Dedupe,
+}
+
+impl Strategy {
+    /// the strategy in progress, for the logs
+    pub fn progressive(&self) -> String {
+        match self {
+            Strategy::Dedupe => "deduping".to_string(),
+            _ => format!("{self}ing"),
+        }
+    }
+
+    /// the strategy once done, for the logs
+    pub fn past(&self) -> String {
+        match self {
+            Strategy::Dedupe => "deduped".to_string(),
+            _ => format!("{self}ed"),
+        }
+    }
 }
@@ -41,6 +41,69 @@ pub fn inos(path: &assert_fs::fixture::ChildPath) -> (u64, u64) {
     (metadata.dev(), metadata.ino())
 }
 
+/// returns the space allocated to the file, in bytes
+#[allow(dead_code)]
+pub fn allocated(path: &assert_fs::fixture::ChildPath) -> u64 {
+    fs::metadata(path.path()).unwrap().blocks() * 512
+}
+
+/// does the filesystem of the directory support the kernel deduplication
+///
+/// Probed with the FIDEDUPERANGE ioctl on two identical files.
+#[cfg(target_os = "linux")]
+pub fn supports_dedupe(dir: &std::path::Path) -> bool {
+    use std::os::unix::io::AsRawFd;
+
+    const FIDEDUPERANGE: libc::c_ulong = 0xC018_9436;
+
+    #[repr(C)]
+    struct FileDedupeRange {
+        src_offset: u64,
+        src_length: u64,
+        dest_count: u16,
+        reserved1: u16,
+        reserved2: u32,
+        dest_fd: i64,
+        dest_offset: u64,
+        bytes_deduped: u64,
+        status: i32,
+        reserved: u32,
+    }
+
+    let src_path = dir.join(".dedupe-probe-src");
+    let dest_path = dir.join(".dedupe-probe-dest");
+    fs::write(&src_path, [1; 4096]).unwrap();
+    fs::write(&dest_path, [1; 4096]).unwrap();
+    let src = fs::File::open(&src_path).unwrap();
+    let dest = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(&dest_path)
+        .unwrap();
+    let mut range = FileDedupeRange {
+        src_offset: 0,
+        src_length: 4096,
+        dest_count: 1,
+        reserved1: 0,
+        reserved2: 0,
+        dest_fd: dest.as_raw_fd() as i64,
+        dest_offset: 0,
+        bytes_deduped: 0,
+        status: 0,
+        reserved: 0,
+    };
+    // SAFETY: the range has room for the single destination it declares
+    let res = unsafe { libc::ioctl(src.as_raw_fd(), FIDEDUPERANGE as _, &mut range) };
+    fs::remove_file(&src_path).unwrap();
+    fs::remove_file(&dest_path).unwrap();
+    res == 0 && range.status == 0 && range.bytes_deduped > 0
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn supports_dedupe(_dir: &std::path::Path) -> bool {
+    false
+}
+
 #[macro_export]
 macro_rules! cargo_bin {
     ( $name:expr ) => {
@@ -32,10 +32,49 @@ fn parallel() {
         .stderr(contains("debug: using 5 threads at most"));
 }
 
+#[test]
+fn io_and_hash_threads() {
+    let _cache_dir = setup_cache_dir();
+    let lorem_ipsum = lipsum(100);
+    // set up the test dir
+    let tmp = assert_fs::TempDir::new().unwrap();
+    let foo = tmp.child("foo.txt");
+    let bar = tmp.child("bar.txt");
+    foo.write_str(&lorem_ipsum).unwrap();
+    bar.write_str(&lorem_ipsum).unwrap();
+
+    hld!(
+        "--log-level",
+        "debug",
+        "--io-threads",
+        "2",
+        "--hash-threads",
+        "3",
+        "--io-threads-per-device",
+        "1",
+        "--hash-io",
+        "mmap",
+        tmp.child("*.txt")
+    )
+    .success()
+    .stdout(is_empty())
+    .stderr(
+        contains("debug: using 2 I/O threads at most")
+            .and(contains("debug: using 3 hashing threads at most"))
+            .and(contains("debug: using 1 I/O threads per device at most"))
+            .and(contains(format!(
+                "{} saved in the deduplication of 1 files",
+                pretty_bytes::converter::convert(allocated(&foo) as f64)
+            ))),
+    );
+
+    assert_eq!(inos(&foo), inos(&bar));
+}
+
 #[test]
 fn invalid_glob() {
     hld!("foua/[etsin")
-        .failure()
+        .code(2)
         .stdout(is_empty())
         .stderr(contains(
             "error: foua/[etsin: Pattern syntax error near position 5: invalid range pattern",
@@ -60,7 +99,7 @@ fn deduplication() {
         .stdout(is_empty())
         .stderr(contains(format!(
             "{} saved in the deduplication of 1 files",
-            pretty_bytes::converter::convert(lorem_ipsum.len() as f64)
+            pretty_bytes::converter::convert(allocated(&foo) as f64)
         )));
 
     assert_eq!(inos(&foo), inos(&bar));
@@ -96,7 +135,7 @@ fn dryrun() {
     .stdout(is_empty())
     .stderr(contains(format!(
         "{} saved in the deduplication of 1 files",
-        pretty_bytes::converter::convert(lorem_ipsum.len() as f64)
+        pretty_bytes::converter::convert(allocated(&foo) as f64)
     )));
 
     assert_ne!(inos(&foo), inos(&bar));
@@ -110,11 +149,14 @@ fn unreadable_file() {
     // set up the test dir
     let tmp = assert_fs::TempDir::new().unwrap();
     let foo = tmp.child("foo.txt");
+    let bar = tmp.child("bar.txt");
     foo.write_str(&lorem_ipsum).unwrap();
+    // a file with the same size, so the content of foo has to be read
+    bar.write_str(&lorem_ipsum).unwrap();
 
     fs::set_permissions(foo.path(), Permissions::from_mode(0o000)).unwrap();
 
-    hld!(foo)
+    hld!(tmp.child("*.txt"))
         .failure()
         .stdout(is_empty())
         .stderr(contains(format!(
@@ -123,6 +165,105 @@ fn unreadable_file() {
         )));
 }
 
+#[test]
+fn keep_going() {
+    let _cache_dir = setup_cache_dir();
+    let lorem_ipsum = lipsum(100);
+    // set up the test dir
+    let tmp = assert_fs::TempDir::new().unwrap();
+    let foo = tmp.child("foo.txt");
+    let bar = tmp.child("bar.txt");
+    let baz = tmp.child("baz.txt");
+    foo.write_str(&lorem_ipsum).unwrap();
+    bar.write_str(&lorem_ipsum).unwrap();
+    baz.write_str(&lorem_ipsum).unwrap();
+
+    fs::set_permissions(foo.path(), Permissions::from_mode(0o000)).unwrap();
+
+    hld!("--keep-going", tmp.child("*.txt"))
+        .code(3)
+        .stdout(is_empty())
+        .stderr(
+            contains(format!(
+                "warn: {}: Permission denied (os error 13)",
+                foo.path().display()
+            ))
+            .and(contains("saved in the deduplication of 1 files"))
+            .and(contains(
+                "warn: 1 files skipped while hashing: permission denied",
+            ))
+            .and(contains("error: 1 files skipped after an error")),
+        );
+    assert_eq!(inos(&bar), inos(&baz));
+
+    // an unreadable directory doesn't stop the discovery of the next ones
+    let walk = tmp.child("walk");
+    let locked = walk.child("b");
+    let qux = walk.child("c/qux.txt");
+    let quux = walk.child("c/quux.txt");
+    walk.child("a/foo.txt").write_str(&lorem_ipsum).unwrap();
+    locked.child("foo.txt").write_str(&lorem_ipsum).unwrap();
+    qux.write_str(&lorem_ipsum).unwrap();
+    quux.write_str(&lorem_ipsum).unwrap();
+    fs::set_permissions(locked.path(), Permissions::from_mode(0o000)).unwrap();
+
+    let assert = hld!("--keep-going", "--recursive", walk).code(3);
+    fs::set_permissions(locked.path(), Permissions::from_mode(0o755)).unwrap();
+    let stderr = String::from_utf8(assert.get_output().stderr.clone()).unwrap();
+    assert!(stderr.contains("saved in the deduplication of 2 files"));
+    assert!(stderr.contains("warn: 1 files skipped while discovering: permission denied"));
+    // reported once, even if the files are walked twice
+    assert_eq!(stderr.matches("Permission denied").count(), 1);
+    assert_eq!(inos(&qux), inos(&quux));
+}
+
+#[test]
+fn corrupted_cache() {
+    let cache_dir = setup_cache_dir();
+    // a cache saved by a previous version is dropped
+    cache_dir.child("digests").write_str("not a cache").unwrap();
+    hld!().success().stdout(is_empty()).stderr(contains(format!(
+        "warn: {}: dropping the cache saved in another format",
+        cache_dir.child("digests").path().display()
+    )));
+
+    cache_dir
+        .child("digests")
+        .write_binary(b"HLDC\x01\x00\x00\x00not a cache")
+        .unwrap();
+
+    hld!().code(4).stdout(is_empty()).stderr(contains(format!(
+        "error: {}: corrupted cache",
+        cache_dir.child("digests").path().display()
+    )));
+
+    hld!("--clear-cache")
+        .success()
+        .stdout(is_empty())
+        .stderr(contains("0 B saved in the deduplication of 0 files"));
+}
+
+#[test]
+fn detailed_exit_codes() {
+    let _cache_dir = setup_cache_dir();
+    let lorem_ipsum = lipsum(100);
+    // set up the test dir
+    let tmp = assert_fs::TempDir::new().unwrap();
+    let foo = tmp.child("foo.txt");
+    let bar = tmp.child("bar.txt");
+    foo.write_str(&lorem_ipsum).unwrap();
+    bar.write_str(&lorem_ipsum).unwrap();
+
+    hld!("--detailed-exit-codes", tmp.child("*.txt"))
+        .success()
+        .stderr(contains("saved in the deduplication of 1 files"));
+    // nothing more to do
+    hld!("--detailed-exit-codes", tmp.child("*.txt"))
+        .code(5)
+        .stderr(contains("0 B saved in the deduplication of 0 files"));
+    hld!(tmp.child("*.txt")).success();
+}
+
 #[test]
 fn no_deduplication_different_files() {
     let _cache_dir = setup_cache_dir();
@@ -308,7 +449,7 @@ fn recursive() {
         .stdout(is_empty())
         .stderr(contains(format!(
             "{} saved in the deduplication of 1 files",
-            pretty_bytes::converter::convert(lorem_ipsum.len() as f64)
+            pretty_bytes::converter::convert(allocated(&foo) as f64)
         )));
 
     assert_eq!(inos(&foo), inos(&bar));
@@ -334,7 +475,7 @@ fn symlinking() {
         .stdout(is_empty())
         .stderr(contains(format!(
             "{} saved in the deduplication of 1 files",
-            pretty_bytes::converter::convert(lorem_ipsum.len() as f64)
+            pretty_bytes::converter::convert(allocated(&foo) as f64)
         )));
 
     assert_eq!(inos(&foo), inos(&bar));
@@ -344,3 +485,1481 @@ fn symlinking() {
         fs::read_link(bar.path()).unwrap_or(bar.path().to_path_buf())
     );
 }
+
+#[test]
+fn unique_size_not_hashed() {
+    let _cache_dir = setup_cache_dir();
+    let tmp = assert_fs::TempDir::new().unwrap();
+    let foo = tmp.child("foo.txt");
+    let bar = tmp.child("bar.txt");
+    foo.write_str(&lipsum(100)).unwrap();
+    bar.write_str(&lipsum(200)).unwrap();
+
+    hld!("--log-level", "debug", tmp.child("*.txt"))
+        .success()
+        .stdout(is_empty())
+        .stderr(
+            contains("debug: computing digest of")
+                .not()
+                .and(contains("debug: 0 candidate files for the deduplication"))
+                .and(contains("0 B saved in the deduplication of 0 files")),
+        );
+}
+
+#[test]
+fn follow_symlinks() {
+    let _cache_dir = setup_cache_dir();
+    let lorem_ipsum = lipsum(100);
+    // set up the test dir
+    let tmp = assert_fs::TempDir::new().unwrap();
+    let foo = tmp.child("foo.txt");
+    let bar = tmp.child("links").child("bar.txt");
+    let baz = tmp.child("links").child("baz.txt");
+    tmp.child("links").mkdir_all().unwrap();
+    foo.write_str(&lorem_ipsum).unwrap();
+    bar.write_str(&lorem_ipsum).unwrap();
+    baz.symlink_to_file(foo.path()).unwrap();
+
+    // symlinks are ignored by default
+    hld!(tmp.child("links").child("*.txt"))
+        .success()
+        .stdout(is_empty())
+        .stderr(contains("0 B saved in the deduplication of 0 files"));
+
+    assert_ne!(inos(&foo), inos(&bar));
+
+    hld!(
+        "--log-level",
+        "debug",
+        tmp.child("links").child("*.txt"),
+        "--follow-symlinks"
+    )
+    .success()
+    .stdout(is_empty())
+    .stderr(
+        contains(format!(
+            "debug: following {} to {}",
+            baz.path().display(),
+            fs::canonicalize(foo.path()).unwrap().display()
+        ))
+        .and(contains(format!(
+            "{} saved in the deduplication of 1 files",
+            pretty_bytes::converter::convert(allocated(&foo) as f64)
+        ))),
+    );
+
+    assert_eq!(inos(&foo), inos(&bar));
+    baz.assert(is_symlink());
+}
+
+#[test]
+fn follow_symlinks_loop() {
+    let _cache_dir = setup_cache_dir();
+    let tmp = assert_fs::TempDir::new().unwrap();
+    let foo = tmp.child("foo.txt");
+    let bar = tmp.child("bar.txt");
+    foo.symlink_to_file(bar.path()).unwrap();
+    bar.symlink_to_file(foo.path()).unwrap();
+
+    hld!(tmp.child("*.txt"), "--follow-symlinks")
+        .success()
+        .stdout(is_empty())
+        .stderr(
+            contains(format!(
+                "warn: {}: symbolic link loop detected",
+                foo.path().display()
+            ))
+            .and(contains("0 B saved in the deduplication of 0 files")),
+        );
+}
+
+#[test]
+fn dedup_symlinks() {
+    let _cache_dir = setup_cache_dir();
+    let tmp = assert_fs::TempDir::new().unwrap();
+    let foo = tmp.child("foo.txt");
+    let bar = tmp.child("bar.txt");
+    let baz = tmp.child("baz.txt");
+    foo.symlink_to_file("target.txt").unwrap();
+    bar.symlink_to_file("target.txt").unwrap();
+    baz.symlink_to_file("other.txt").unwrap();
+
+    let symlink_ino = |path: &assert_fs::fixture::ChildPath| {
+        use std::os::unix::fs::MetadataExt;
+        path.symlink_metadata().unwrap().ino()
+    };
+    assert_ne!(symlink_ino(&foo), symlink_ino(&bar));
+
+    hld!(tmp.child("*.txt"), "--dedup-symlinks")
+        .success()
+        .stdout(is_empty())
+        .stderr(contains("1 symlinks deduplicated"));
+
+    assert_eq!(symlink_ino(&foo), symlink_ino(&bar));
+    assert_ne!(symlink_ino(&foo), symlink_ino(&baz));
+    foo.assert(is_symlink());
+    bar.assert(is_symlink());
+}
+
+#[test]
+fn hash_io() {
+    let _cache_dir = setup_cache_dir();
+    let small = lipsum(100);
+    let large = small.repeat(5 * 1024 * 1024 / small.len() + 1);
+    // set up the test dir
+    let tmp = assert_fs::TempDir::new().unwrap();
+    let foo = tmp.child("foo.txt");
+    let bar = tmp.child("bar.txt");
+    let baz = tmp.child("baz.txt");
+    let qux = tmp.child("qux.txt");
+    foo.write_str(&small).unwrap();
+    bar.write_str(&small).unwrap();
+    baz.write_str(&large).unwrap();
+    qux.write_str(&large).unwrap();
+
+    // the files are only memory mapped when the recently modified ones are skipped
+    hld!(
+        "--log-level",
+        "trace",
+        tmp.child("*.txt"),
+        "--hash-io",
+        "auto",
+        "--dry-run"
+    )
+    .success()
+    .stdout(is_empty())
+    .stderr(
+        contains(format!(
+            "trace: using buffered I/O to hash {}",
+            baz.path().display()
+        ))
+        .and(contains("mmap I/O").not())
+        .and(contains("saved in the deduplication of 2 files")),
+    );
+    hld!(
+        "--log-level",
+        "trace",
+        tmp.child("*.txt"),
+        "--hash-io",
+        "auto",
+        "--min-age",
+        "0s",
+        "--dry-run"
+    )
+    .success()
+    .stdout(is_empty())
+    .stderr(
+        contains(format!(
+            "trace: using buffered I/O to hash {}",
+            foo.path().display()
+        ))
+        .and(contains(format!(
+            "trace: using mmap I/O to hash {}",
+            baz.path().display()
+        )))
+        .and(contains("saved in the deduplication of 2 files")),
+    );
+
+    for hash_io in ["mmap", "buffered"] {
+        hld!(
+            "--log-level",
+            "trace",
+            tmp.child("*.txt"),
+            "--hash-io",
+            hash_io,
+            "--dry-run"
+        )
+        .success()
+        .stdout(is_empty())
+        .stderr(
+            contains(format!(
+                "using {hash_io} I/O to hash {}",
+                foo.path().display()
+            ))
+            .and(contains(format!(
+                "using {hash_io} I/O to hash {}",
+                baz.path().display()
+            )))
+            .and(contains("saved in the deduplication of 2 files")),
+        );
+    }
+}
+
+#[test]
+fn digest_algorithms() {
+    let _cache_dir = setup_cache_dir();
+    let lorem_ipsum = lipsum(100);
+    // set up the test dir
+    let tmp = assert_fs::TempDir::new().unwrap();
+    let foo = tmp.child("foo.txt");
+    let bar = tmp.child("bar.txt");
+    let baz = tmp.child("baz.txt");
+    foo.write_str(&lorem_ipsum).unwrap();
+    bar.write_str(&lorem_ipsum).unwrap();
+    baz.write_str(&lorem_ipsum.to_uppercase()).unwrap();
+
+    for digest in ["blake3", "sha256", "xxh3"] {
+        for hash_io in ["mmap", "buffered"] {
+            hld!(
+                "--log-level",
+                "debug",
+                tmp.child("*.txt"),
+                "--digest",
+                digest,
+                "--hash-io",
+                hash_io,
+                "--dry-run"
+            )
+            .success()
+            .stdout(is_empty())
+            .stderr(
+                contains(format!("debug: using the {digest} digest algorithm"))
+                    .and(contains("saved in the deduplication of 1 files")),
+            );
+        }
+    }
+}
+
+#[test]
+fn cache_digest_algorithm() {
+    let lorem_ipsum = lipsum(100);
+    // set up the test dir
+    let tmp = assert_fs::TempDir::new().unwrap();
+    let foo = tmp.child("foo.txt");
+    foo.write_str(&lorem_ipsum).unwrap();
+
+    let cache_dir = assert_fs::TempDir::new().unwrap();
+    let cache_path = cache_dir.child("digests");
+
+    // first warm up the cache
+    hld!("--cache", foo, "--cache-path", cache_path)
+        .success()
+        .stdout(is_empty());
+
+    // the cached digest is reused with the same algorithm
+    hld!(
+        "--log-level",
+        "debug",
+        "--cache",
+        foo,
+        "--cache-path",
+        cache_path,
+        "--digest",
+        "blake3"
+    )
+    .success()
+    .stdout(is_empty())
+    .stderr(
+        contains(format!(
+            "debug: computing digest of {}",
+            foo.path().display()
+        ))
+        .not()
+        .and(contains("debug: saving updated cache").not()),
+    );
+
+    // and recomputed with another one
+    hld!(
+        "--log-level",
+        "debug",
+        "--cache",
+        foo,
+        "--cache-path",
+        cache_path,
+        "--digest",
+        "sha256"
+    )
+    .success()
+    .stdout(is_empty())
+    .stderr(
+        contains(format!(
+            "debug: computing digest of {}",
+            foo.path().display()
+        ))
+        .and(contains("debug: saving updated cache")),
+    );
+}
+
+#[test]
+fn verify() {
+    let _cache_dir = setup_cache_dir();
+    let lorem_ipsum = lipsum(100);
+    // set up the test dir
+    let tmp = assert_fs::TempDir::new().unwrap();
+    let foo = tmp.child("foo.txt");
+    let bar = tmp.child("bar.txt");
+    foo.write_str(&lorem_ipsum).unwrap();
+    bar.write_str(&lorem_ipsum).unwrap();
+
+    hld!(
+        "--log-level",
+        "debug",
+        tmp.child("*.txt"),
+        "--digest",
+        "xxh3",
+        "--verify"
+    )
+    .success()
+    .stdout(is_empty())
+    .stderr(contains("debug: verifying").and(contains(format!(
+        "{} saved in the deduplication of 1 files",
+        pretty_bytes::converter::convert(allocated(&foo) as f64)
+    ))));
+
+    assert_eq!(inos(&foo), inos(&bar));
+}
+
+#[test]
+fn digest_sidecar() {
+    let _cache_dir = setup_cache_dir();
+    let lorem_ipsum = lipsum(100);
+    let digest = "0123456789abcdef0123456789abcdef01234567";
+    // set up the test dir
+    let tmp = assert_fs::TempDir::new().unwrap();
+    let foo = tmp.child("foo.jar");
+    let bar = tmp.child("bar.jar");
+    foo.write_str(&lorem_ipsum).unwrap();
+    bar.write_str(&lorem_ipsum).unwrap();
+    tmp.child("foo.jar.sha1").write_str(digest).unwrap();
+    tmp.child("bar.jar.sha1")
+        .write_str(&format!("{digest}  bar.jar\n"))
+        .unwrap();
+
+    hld!(
+        "--log-level",
+        "debug",
+        tmp.child("*.jar"),
+        "--digest",
+        "sha1",
+        "--digest-sidecar"
+    )
+    .success()
+    .stdout(is_empty())
+    .stderr(
+        contains("debug: computing digest of")
+            .not()
+            .and(contains(format!(
+                "debug: using the external digest of {}",
+                foo.path().display()
+            )))
+            .and(contains(format!(
+                "{} saved in the deduplication of 1 files",
+                pretty_bytes::converter::convert(allocated(&foo) as f64)
+            ))),
+    );
+
+    assert_eq!(inos(&foo), inos(&bar));
+
+    // a sidecar file older than its file is ignored
+    let baz = tmp.child("rebuilt/baz.jar");
+    let qux = tmp.child("rebuilt/qux.jar");
+    baz.write_str(&lorem_ipsum.to_uppercase()).unwrap();
+    qux.write_str(&lorem_ipsum.to_lowercase()).unwrap();
+    for sidecar in ["rebuilt/baz.jar.sha1", "rebuilt/qux.jar.sha1"] {
+        tmp.child(sidecar).write_str(digest).unwrap();
+    }
+    let mtime = filetime::FileTime::from_unix_time(1_000_000_000, 0);
+    filetime::set_file_mtime(tmp.child("rebuilt/qux.jar.sha1").path(), mtime).unwrap();
+    hld!(
+        "--log-level",
+        "debug",
+        tmp.child("rebuilt/*.jar"),
+        "--digest",
+        "sha1",
+        "--digest-sidecar"
+    )
+    .success()
+    .stdout(is_empty())
+    .stderr(
+        contains(format!(
+            "warn: {}: older than {} - computing its digest",
+            tmp.child("rebuilt/qux.jar.sha1").path().display(),
+            qux.path().display()
+        ))
+        .and(contains(format!(
+            "debug: computing digest of {}",
+            qux.path().display()
+        )))
+        .and(contains("0 B saved in the deduplication of 0 files")),
+    );
+    assert_ne!(inos(&baz), inos(&qux));
+    qux.assert(lorem_ipsum.to_lowercase().as_str());
+}
+
+#[test]
+fn digest_manifest() {
+    let _cache_dir = setup_cache_dir();
+    let lorem_ipsum = lipsum(100);
+    let digest = "0123456789abcdef".repeat(4);
+    // set up the test dir
+    let tmp = assert_fs::TempDir::new().unwrap();
+    let foo = tmp.child("foo.txt");
+    let bar = tmp.child("bar.txt");
+    let baz = tmp.child("baz.txt");
+    foo.write_str(&lorem_ipsum).unwrap();
+    bar.write_str(&lorem_ipsum.to_uppercase()).unwrap();
+    baz.write_str(&lorem_ipsum).unwrap();
+    let manifest = tmp.child("SHA256SUMS");
+    manifest
+        .write_str(&format!(
+            "{digest}  {}\n{digest} *{}\n",
+            foo.path().display(),
+            bar.path().display()
+        ))
+        .unwrap();
+
+    // the manifest is trusted, but the content is checked with --verify
+    hld!(
+        "--log-level",
+        "debug",
+        tmp.child("*.txt"),
+        "--digest",
+        "sha256",
+        "--digest-manifest",
+        manifest,
+        "--verify"
+    )
+    .success()
+    .stdout(is_empty())
+    .stderr(
+        contains(format!(
+            "debug: computing digest of {}",
+            foo.path().display()
+        ))
+        .not()
+        .and(contains(format!(
+            "debug: computing digest of {}",
+            baz.path().display()
+        )))
+        .and(contains(format!(
+            "warn: {} and {} have the same digest but a different content",
+            bar.path().display(),
+            foo.path().display()
+        )))
+        .and(contains("0 B saved in the deduplication of 0 files")),
+    );
+
+    assert_ne!(inos(&foo), inos(&bar));
+    assert_ne!(inos(&foo), inos(&baz));
+}
+
+#[test]
+fn invalid_digest_manifest() {
+    let _cache_dir = setup_cache_dir();
+    let tmp = assert_fs::TempDir::new().unwrap();
+    let manifest = tmp.child("SHA256SUMS");
+    manifest.write_str("abcd  foo.txt\n").unwrap();
+
+    hld!("--digest-manifest", manifest)
+        .code(2)
+        .stdout(is_empty())
+        .stderr(contains(format!(
+            "error: {}:1: invalid digest line",
+            manifest.path().display()
+        )));
+}
+
+#[test]
+fn print_digests() {
+    let _cache_dir = setup_cache_dir();
+    // set up the test dir
+    let tmp = assert_fs::TempDir::new().unwrap();
+    let foo = tmp.child("foo.txt");
+    let bar = tmp.child("bar.txt");
+    foo.write_str("hello world\n").unwrap();
+    bar.write_str("hello world\n").unwrap();
+
+    hld!(tmp.child("*.txt"), "--print-digests", "--digest", "sha256")
+        .success()
+        .stdout(
+            contains(format!(
+                "a948904f2f0f479b8f8197694b30184b0d2ed1c1cd2a1ec0fb85d299a192a447  {}\n",
+                foo.path().display()
+            ))
+            .and(contains(format!(
+                "a948904f2f0f479b8f8197694b30184b0d2ed1c1cd2a1ec0fb85d299a192a447  {}\n",
+                bar.path().display()
+            ))),
+        )
+        .stderr(is_empty());
+
+    // nothing has been deduplicated
+    assert_ne!(inos(&foo), inos(&bar));
+
+    hld!(tmp.child("*.txt"), "--print-digests", "--digest", "sha1")
+        .success()
+        .stdout(contains(format!(
+            "22596363b3de40b06f981fb85d82312e8c0ed511  {}\n",
+            foo.path().display()
+        )))
+        .stderr(is_empty());
+
+    // a closed output is the normal end of the digests, like with `| head`
+    let mut child = Command::new(cargo_bin!("hld"))
+        .args([
+            tmp.child("*.txt").to_string(),
+            "--print-digests".to_string(),
+        ])
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .spawn()
+        .unwrap();
+    drop(child.stdout.take());
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stderr), "");
+}
+
+#[test]
+fn import_digests() {
+    let lorem_ipsum = lipsum(100);
+    // set up the test dir
+    let tmp = assert_fs::TempDir::new().unwrap();
+    let foo = tmp.child("foo.txt");
+    let bar = tmp.child("bar.txt");
+    foo.write_str(&lorem_ipsum).unwrap();
+    bar.write_str(&lorem_ipsum).unwrap();
+
+    let cache_dir = assert_fs::TempDir::new().unwrap();
+    let cache_path = cache_dir.child("digests");
+    let digests = cache_dir.child("B3SUMS");
+
+    // export the digests computed on some host
+    let output = Command::new(cargo_bin!("hld"))
+        .args([foo.to_string(), "--print-digests".to_string()])
+        .env("HLD_CACHE_PATH", cache_path.path())
+        .output()
+        .unwrap();
+    digests.write_binary(&output.stdout).unwrap();
+
+    // and import them in the cache on another one
+    hld!(
+        "--log-level",
+        "debug",
+        "--cache",
+        foo,
+        "--cache-path",
+        cache_path,
+        "--clear-cache",
+        "--import-digests",
+        digests,
+        bar
+    )
+    .success()
+    .stdout(is_empty())
+    .stderr(
+        contains(format!(
+            "debug: computing digest of {}",
+            foo.path().display()
+        ))
+        .not()
+        .and(contains("debug: saving updated cache"))
+        .and(contains(format!(
+            "{} saved in the deduplication of 1 files",
+            pretty_bytes::converter::convert(allocated(&foo) as f64)
+        ))),
+    );
+
+    assert_eq!(inos(&foo), inos(&bar));
+}
+
+#[test]
+fn progress() {
+    let _cache_dir = setup_cache_dir();
+    let lorem_ipsum = lipsum(100);
+    // set up the test dir
+    let tmp = assert_fs::TempDir::new().unwrap();
+    tmp.child("foo.txt").write_str(&lorem_ipsum).unwrap();
+    tmp.child("bar.txt").write_str(&lorem_ipsum).unwrap();
+    tmp.child("baz.txt").write_str(&lipsum(10)).unwrap();
+
+    hld!("--log-level", "debug", "--progress", tmp.child("*.txt"))
+        .success()
+        .stdout(is_empty())
+        .stderr(contains(format!(
+            "debug: 3 files found, 3/3 stat'ed, {size}/{size} hashed, 1 duplicate groups, 1 links",
+            size = pretty_bytes::converter::convert(2.0 * lorem_ipsum.len() as f64)
+        )));
+}
+
+#[test]
+fn interrupt_and_resume() {
+    use std::io::Read;
+    use std::process::Stdio;
+
+    // set up the test dir, with enough files to fill the stderr pipe with the logs
+    let tmp = assert_fs::TempDir::new().unwrap();
+    for i in 0..2000 {
+        tmp.child(format!("{i:04}.txt"))
+            .write_str(&format!("{i:064}"))
+            .unwrap();
+    }
+    let cache_dir = assert_fs::TempDir::new().unwrap();
+    let checkpoint = cache_dir.child("digests.checkpoint");
+
+    // the process blocks while the stderr pipe is full, so it is still running
+    // when interrupted
+    let mut child = Command::new(cargo_bin!("hld"))
+        .args(["--log-level", "debug", "-j", "2"])
+        .arg(tmp.child("*.txt").path())
+        .env("HLD_CACHE_PATH", cache_dir.child("digests").path())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    std::thread::sleep(std::time::Duration::from_millis(500));
+    Command::new("kill")
+        .args(["-INT", &child.id().to_string()])
+        .assert()
+        .success();
+    let mut stderr = String::new();
+    child
+        .stderr
+        .take()
+        .unwrap()
+        .read_to_string(&mut stderr)
+        .unwrap();
+    assert_eq!(child.wait().unwrap().code(), Some(130));
+    assert!(stderr.contains("warn: interrupted - finishing the operations in progress"));
+    assert!(stderr.contains("error: interrupted"));
+    checkpoint.assert(exists());
+
+    // the digests computed before the interruption are reused
+    hld!(
+        "--log-level",
+        "debug",
+        tmp.child("*.txt"),
+        "--cache-path",
+        cache_dir.child("digests"),
+        "--resume"
+    )
+    .success()
+    .stdout(is_empty())
+    .stderr(
+        contains("debug: using the checkpointed digest of")
+            .and(contains("0 B saved in the deduplication of 0 files")),
+    );
+    checkpoint.assert(missing());
+}
+
+#[test]
+fn interrupt_twice() {
+    use std::process::Stdio;
+
+    let _cache_dir = setup_cache_dir();
+    // set up the test dir, with a fifo blocking the import of the digests
+    let tmp = assert_fs::TempDir::new().unwrap();
+    tmp.child("foo.txt").write_str("foo").unwrap();
+    let fifo = tmp.child("digests.fifo");
+    Command::new("mkfifo").arg(fifo.path()).assert().success();
+
+    let child = Command::new(cargo_bin!("hld"))
+        .arg("--import-digests")
+        .arg(fifo.path())
+        .arg(tmp.child("*.txt").path())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    for _ in 0..2 {
+        std::thread::sleep(std::time::Duration::from_millis(500));
+        Command::new("kill")
+            .args(["-TERM", &child.id().to_string()])
+            .assert()
+            .success();
+    }
+    let output = child.wait_with_output().unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(130));
+    assert!(stderr.contains("warn: interrupted - finishing the operations in progress"));
+    assert!(stderr.contains("error: interrupted again - exiting now"));
+}
+
+#[test]
+fn interrupt_while_locked() {
+    use std::io::Read;
+    use std::process::Stdio;
+
+    // set up the test dir, with enough files to fill the stderr pipe with the logs
+    let tmp = assert_fs::TempDir::new().unwrap();
+    for i in 0..2000 {
+        tmp.child(format!("{i:04}.txt"))
+            .write_str(&format!("{i:064}"))
+            .unwrap();
+    }
+    let cache_dir = assert_fs::TempDir::new().unwrap();
+    let checkpoint = cache_dir.child("digests.checkpoint");
+
+    let mut child = Command::new(cargo_bin!("hld"))
+        .args(["--log-level", "debug", "-j", "2", "--cache-all"])
+        .arg(tmp.child("*.txt").path())
+        .env("HLD_CACHE_PATH", cache_dir.child("digests").path())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    std::thread::sleep(std::time::Duration::from_millis(500));
+    // another process locks the cache before the interruption
+    let lock_file = fs::File::create(cache_dir.child("digests.lock").path()).unwrap();
+    fs2::FileExt::lock_exclusive(&lock_file).unwrap();
+    Command::new("kill")
+        .args(["-INT", &child.id().to_string()])
+        .assert()
+        .success();
+    let mut stderr = String::new();
+    child
+        .stderr
+        .take()
+        .unwrap()
+        .read_to_string(&mut stderr)
+        .unwrap();
+    assert_eq!(child.wait().unwrap().code(), Some(130));
+    assert!(stderr.contains("error: interrupted"));
+    // the checkpoint is saved even though the cache can't be updated
+    checkpoint.assert(exists());
+}
+
+#[test]
+fn cache_all() {
+    let lorem_ipsum = lipsum(100);
+    // set up the test dir
+    let tmp = assert_fs::TempDir::new().unwrap();
+    let foo = tmp.child("foo.txt");
+    let bar = tmp.child("bar.txt");
+    foo.write_str(&lorem_ipsum).unwrap();
+    bar.write_str(&lorem_ipsum.to_uppercase()).unwrap();
+
+    let cache_dir = assert_fs::TempDir::new().unwrap();
+    let cache_path = cache_dir.child("digests");
+
+    // first warm up the cache
+    hld!(
+        "--log-level",
+        "debug",
+        tmp.child("*.txt"),
+        "--cache-path",
+        cache_path,
+        "--cache-all"
+    )
+    .success()
+    .stdout(is_empty())
+    .stderr(
+        contains(format!(
+            "debug: computing digest of {}",
+            foo.path().display()
+        ))
+        .and(contains("debug: saving updated cache")),
+    );
+
+    // the unchanged files are not hashed again
+    hld!(
+        "--log-level",
+        "debug",
+        tmp.child("*.txt"),
+        "--cache-path",
+        cache_path,
+        "--cache-all"
+    )
+    .success()
+    .stdout(is_empty())
+    .stderr(contains("debug: computing digest of").not());
+
+    // but the modified ones are
+    std::thread::sleep(std::time::Duration::from_millis(10));
+    bar.write_str(&lorem_ipsum).unwrap();
+    hld!(
+        "--log-level",
+        "debug",
+        tmp.child("*.txt"),
+        "--cache-path",
+        cache_path,
+        "--cache-all"
+    )
+    .success()
+    .stdout(is_empty())
+    .stderr(
+        contains(format!(
+            "debug: computing digest of {}",
+            foo.path().display()
+        ))
+        .not()
+        .and(contains(format!(
+            "debug: computing digest of {}",
+            bar.path().display()
+        )))
+        .and(contains(format!(
+            "{} saved in the deduplication of 1 files",
+            pretty_bytes::converter::convert(allocated(&foo) as f64)
+        ))),
+    );
+
+    assert_eq!(inos(&foo), inos(&bar));
+}
+
+#[test]
+fn cache_all_modified_in_place() {
+    let lorem_ipsum = lipsum(100);
+    // set up the test dir
+    let tmp = assert_fs::TempDir::new().unwrap();
+    let foo = tmp.child("foo.txt");
+    let bar = tmp.child("bar.txt");
+    let qux = tmp.child("qux.txt");
+    foo.write_str(&lorem_ipsum).unwrap();
+    bar.write_str(&lorem_ipsum).unwrap();
+
+    let cache_dir = assert_fs::TempDir::new().unwrap();
+    let cache_path = cache_dir.child("digests");
+
+    hld!(
+        tmp.child("*.txt"),
+        "--cache-path",
+        cache_path,
+        "--cache-all"
+    )
+    .success()
+    .stderr(contains("saved in the deduplication of 1 files"));
+    assert_eq!(inos(&foo), inos(&bar));
+
+    // the stamps of the linked files are saved after their linking
+    hld!(
+        "--log-level",
+        "debug",
+        tmp.child("*.txt"),
+        "--cache-path",
+        cache_path,
+        "--cache-all"
+    )
+    .success()
+    .stderr(contains("0 stale entries"));
+
+    // bar is modified in place, with the same size and modification time, and
+    // qux gets its previous content
+    let mtime = filetime::FileTime::from_last_modification_time(&fs::metadata(bar.path()).unwrap());
+    bar.write_str(&lorem_ipsum.to_uppercase()).unwrap();
+    filetime::set_file_mtime(bar.path(), mtime).unwrap();
+    qux.write_str(&lorem_ipsum).unwrap();
+    hld!(
+        "--log-level",
+        "debug",
+        tmp.child("*.txt"),
+        "--cache-path",
+        cache_path,
+        "--cache-all"
+    )
+    .success()
+    .stderr(
+        contains("using the cached digest of the inode of")
+            .not()
+            .and(contains("0 B saved in the deduplication of 0 files")),
+    );
+    assert_ne!(inos(&bar), inos(&qux));
+    qux.assert(lorem_ipsum.as_str());
+    bar.assert(lorem_ipsum.to_uppercase().as_str());
+}
+
+#[test]
+fn concurrent_cache_updates() {
+    // set up the test dir, with a cached directory per process
+    let tmp = assert_fs::TempDir::new().unwrap();
+    for dir in ["a", "b"] {
+        for i in 0..100 {
+            tmp.child(format!("{dir}/{i:03}.txt"))
+                .write_str(&format!("{dir}{i:064}"))
+                .unwrap();
+        }
+    }
+    let cache_dir = assert_fs::TempDir::new().unwrap();
+
+    // both processes update the cache at the same time
+    let children: Vec<_> = ["a", "b"]
+        .iter()
+        .map(|dir| {
+            Command::new(cargo_bin!("hld"))
+                .arg("--cache")
+                .arg(tmp.child(format!("{dir}/*.txt")).path())
+                .env("HLD_CACHE_PATH", cache_dir.child("digests").path())
+                .stderr(std::process::Stdio::null())
+                .spawn()
+                .unwrap()
+        })
+        .collect();
+    for mut child in children {
+        assert!(child.wait().unwrap().success());
+    }
+
+    // and none of their digests were lost
+    hld!(
+        "--log-level",
+        "debug",
+        "--cache",
+        tmp.child("a/*.txt"),
+        "--cache",
+        tmp.child("b/*.txt"),
+        "--cache-path",
+        cache_dir.child("digests")
+    )
+    .success()
+    .stdout(is_empty())
+    .stderr(contains("debug: computing digest of").not());
+}
+
+#[test]
+fn cache_renamed_files() {
+    let lorem_ipsum = lipsum(100);
+    // set up the test dir
+    let tmp = assert_fs::TempDir::new().unwrap();
+    let foo = tmp.child("stable/foo.txt");
+    let bar = tmp.child("stable/bar.txt");
+    foo.write_str(&lorem_ipsum).unwrap();
+    bar.write_str(&lorem_ipsum.to_uppercase()).unwrap();
+
+    let cache_dir = assert_fs::TempDir::new().unwrap();
+    let cache_path = cache_dir.child("digests");
+
+    // first warm up the cache
+    hld!(
+        "--log-level",
+        "debug",
+        "--cache",
+        tmp.child("stable/*.txt"),
+        "--cache-path",
+        cache_path
+    )
+    .success()
+    .stdout(is_empty())
+    .stderr(contains("debug: computing digest of"));
+
+    // the renamed files keep their cached digest
+    fs::rename(tmp.child("stable"), tmp.child("moved")).unwrap();
+    hld!(
+        "--log-level",
+        "debug",
+        "--cache",
+        tmp.child("moved/*.txt"),
+        "--cache-path",
+        cache_path
+    )
+    .success()
+    .stdout(is_empty())
+    .stderr(
+        contains("debug: computing digest of")
+            .not()
+            .and(contains(format!(
+                "debug: using the cached digest of the inode of {}",
+                tmp.child("moved/foo.txt").path().display()
+            ))),
+    );
+
+    // and so do the other links to the cached inodes
+    fs::hard_link(tmp.child("moved/foo.txt"), tmp.child("baz.txt")).unwrap();
+    hld!(
+        "--log-level",
+        "debug",
+        tmp.child("*.txt"),
+        "--cache",
+        tmp.child("moved/bar.txt"),
+        "--cache-path",
+        cache_path
+    )
+    .success()
+    .stdout(is_empty())
+    .stderr(contains("debug: computing digest of").not());
+}
+
+#[test]
+fn cache_eviction() {
+    // set up the test dir
+    let tmp = assert_fs::TempDir::new().unwrap();
+    for name in ["a", "b", "c"] {
+        tmp.child(format!("{name}.txt")).write_str(name).unwrap();
+    }
+
+    let cache_dir = assert_fs::TempDir::new().unwrap();
+    let cache_path = cache_dir.child("digests");
+
+    // the cache can't keep all the digests
+    hld!(
+        "--log-level",
+        "debug",
+        "--cache",
+        tmp.child("*.txt"),
+        "--cache-path",
+        cache_path,
+        "--cache-max-entries",
+        2
+    )
+    .success()
+    .stdout(is_empty())
+    .stderr(contains("debug: evicting 1 cache entries").and(contains(
+        "debug: cache: 0 hits, 3 misses, 1 evictions, 0 stale entries",
+    )));
+
+    // so one of them is computed again
+    hld!(
+        "--log-level",
+        "debug",
+        "--cache",
+        tmp.child("*.txt"),
+        "--cache-path",
+        cache_path,
+        "--cache-max-entries",
+        2
+    )
+    .success()
+    .stdout(is_empty())
+    .stderr(contains(
+        "debug: cache: 2 hits, 1 misses, 1 evictions, 0 stale entries",
+    ));
+
+    // and the removed files are reported as stale
+    fs::remove_file(tmp.child("c.txt")).unwrap();
+    hld!(
+        "--log-level",
+        "debug",
+        "--cache",
+        tmp.child("*.txt"),
+        "--cache-path",
+        cache_path,
+        "--cache-max-bytes",
+        1000000
+    )
+    .success()
+    .stdout(is_empty())
+    .stderr(contains("evictions, 1 stale entries"));
+}
+
+#[test]
+fn dedupe() {
+    let lorem_ipsum = lipsum(100);
+    let _cache_dir = setup_cache_dir();
+    // set up the test dir
+    let tmp = assert_fs::TempDir::new().unwrap();
+    let foo = tmp.child("foo.txt");
+    let bar = tmp.child("bar.txt");
+    foo.write_str(&lorem_ipsum).unwrap();
+    bar.write_str(&lorem_ipsum).unwrap();
+
+    // the files are kept in place, whether the filesystem supports the kernel
+    // deduplication or not
+    let assert = hld!("--strategy", "dedupe", tmp.child("*.txt"))
+        .success()
+        .stdout(is_empty());
+    if supports_dedupe(tmp.path()) {
+        assert.stderr(
+            contains("saved in the deduplication of 1 files")
+                .and(contains("1 files deduped"))
+                .and(contains("not supported").not()),
+        );
+    } else {
+        assert.stderr(
+            contains(format!(
+                "warn: {}: the kernel deduplication is not supported by the filesystem",
+                foo.path().display()
+            ))
+            .and(contains("0 B saved in the deduplication of 0 files")),
+        );
+    }
+
+    assert_ne!(inos(&foo), inos(&bar));
+    foo.assert(lorem_ipsum.as_str());
+    bar.assert(lorem_ipsum.as_str());
+}
+
+#[test]
+fn partial_dedup() {
+    let _cache_dir = setup_cache_dir();
+    // set up the test dir, with two files only differing in their second block
+    let tmp = assert_fs::TempDir::new().unwrap();
+    let foo = tmp.child("foo.img");
+    let bar = tmp.child("bar.img");
+    let block = 1024 * 1024;
+    let mut content: Vec<u8> = (0..3 * block).map(|i| (i % 251) as u8).collect();
+    foo.write_binary(&content).unwrap();
+    content[block + 1] = 0;
+    bar.write_binary(&content).unwrap();
+
+    hld!(
+        "--log-level",
+        "debug",
+        "--partial",
+        block,
+        "--dry-run",
+        tmp.child("*.img")
+    )
+    .success()
+    .stdout(is_empty())
+    .stderr(
+        contains("0 B saved in the deduplication of 0 files").and(contains(format!(
+            "{} saved in the partial deduplication of 1 files",
+            pretty_bytes::converter::convert((2 * block) as f64)
+        ))),
+    );
+
+    // the files are kept as is, whether the filesystem supports the kernel
+    // deduplication or not
+    hld!("--partial", block, tmp.child("*.img"))
+        .success()
+        .stdout(is_empty());
+    assert_ne!(inos(&foo), inos(&bar));
+    assert_eq!(fs::read(bar.path()).unwrap(), content);
+}
+
+#[test]
+fn partial_sparse_files() {
+    use std::io::{Seek, SeekFrom, Write};
+
+    let _cache_dir = setup_cache_dir();
+    // set up the test dir, with two huge sparse files of different sizes sharing a
+    // single block - reading their holes would take far too long
+    let tmp = assert_fs::TempDir::new().unwrap();
+    let block = 1024 * 1024;
+    let size = 64 * 1024 * 1024 * 1024;
+    let content: Vec<u8> = (0..block).map(|i| (i % 251) as u8).collect();
+    for (name, offset, len) in [
+        ("foo.img", 3 * block, size),
+        ("bar.img", 1000 * block, size + block),
+    ] {
+        let mut file = fs::File::create(tmp.child(name).path()).unwrap();
+        file.set_len(len).unwrap();
+        file.seek(SeekFrom::Start(offset)).unwrap();
+        file.write_all(&content).unwrap();
+    }
+
+    hld!("--partial", block, "--dry-run", tmp.child("*.img"))
+        .success()
+        .stdout(is_empty())
+        .stderr(contains(format!(
+            "{} saved in the partial deduplication of 1 files",
+            pretty_bytes::converter::convert(block as f64)
+        )));
+}
+
+#[test]
+fn strategy_fallback() {
+    let lorem_ipsum = lipsum(100);
+    let _cache_dir = setup_cache_dir();
+    // set up the test dir
+    let tmp = assert_fs::TempDir::new().unwrap();
+    let foo = tmp.child("foo.txt");
+    let bar = tmp.child("bar.txt");
+    foo.write_str(&lorem_ipsum).unwrap();
+    bar.write_str(&lorem_ipsum).unwrap();
+
+    // the files are hardlinked when the filesystem doesn't support the reflinks
+    let assert = hld!(
+        "--log-level",
+        "debug",
+        "--strategy",
+        "reflink,hardlink",
+        tmp.child("*.txt")
+    )
+    .success()
+    .stdout(is_empty())
+    .stderr(
+        contains(format!(
+            "debug: reflinking {} and {}",
+            bar.path().display(),
+            foo.path().display()
+        ))
+        .and(
+            contains("1 files reflinked").or(contains(format!(
+                "debug: {}: Operation not supported (os error 95) - falling back to hardlink",
+                foo.path().display()
+            ))
+            .and(contains("1 files hardlinked"))),
+        ),
+    );
+    let stderr = String::from_utf8_lossy(&assert.get_output().stderr).to_string();
+    if stderr.contains("1 files hardlinked") {
+        assert_eq!(inos(&foo), inos(&bar));
+    }
+    foo.assert(lorem_ipsum.as_str());
+    // without any temporary file left
+    assert_eq!(fs::read_dir(tmp.path()).unwrap().count(), 2);
+}
+
+#[test]
+#[ignore = "needs TMPDIR on a filesystem with reflinks, like btrfs or XFS"]
+fn shared_extents() {
+    let lorem_ipsum = lipsum(100);
+    let _cache_dir = setup_cache_dir();
+    // set up the test dir, with a reflinked copy
+    let tmp = assert_fs::TempDir::new().unwrap();
+    let foo = tmp.child("foo.txt");
+    let bar = tmp.child("bar.txt");
+    foo.write_str(&lorem_ipsum).unwrap();
+    Command::new("cp")
+        .arg("--reflink=always")
+        .arg(foo.path())
+        .arg(bar.path())
+        .assert()
+        .success();
+
+    // nothing to save by sharing the extents again
+    hld!(
+        "--log-level",
+        "debug",
+        "--strategy",
+        "reflink",
+        tmp.child("*.txt")
+    )
+    .success()
+    .stdout(is_empty())
+    .stderr(
+        contains(format!(
+            "debug: {} and {} already share their extents",
+            bar.path().display(),
+            foo.path().display()
+        ))
+        .and(contains("0 B saved in the deduplication of 0 files"))
+        .and(contains(format!(
+            "{} already shared by 1 files",
+            pretty_bytes::converter::convert(allocated(&foo) as f64)
+        ))),
+    );
+    assert_ne!(inos(&foo), inos(&bar));
+}
+
+#[test]
+fn savings() {
+    let lorem_ipsum = lipsum(100);
+    let _cache_dir = setup_cache_dir();
+    // set up the test dir
+    let tmp = assert_fs::TempDir::new().unwrap();
+    let foo = tmp.child("foo.txt");
+    let bar = tmp.child("bar.txt");
+    let baz = tmp.child("baz.txt");
+    let qux = tmp.child("qux.dat");
+    foo.write_str(&lorem_ipsum).unwrap();
+    bar.write_str(&lorem_ipsum).unwrap();
+    baz.write_str(&lorem_ipsum).unwrap();
+    // another link to foo keeps its data on the disk
+    fs::hard_link(foo.path(), qux.path()).unwrap();
+
+    hld!(tmp.child("*.txt"))
+        .success()
+        .stdout(is_empty())
+        .stderr(contains(format!(
+            "{} saved in the deduplication of 2 files",
+            pretty_bytes::converter::convert(allocated(&baz) as f64)
+        )));
+    assert_eq!(inos(&foo), inos(&bar));
+    assert_eq!(inos(&foo), inos(&baz));
+    assert_ne!(inos(&foo), inos(&qux));
+
+    // nothing more to save on the next run
+    hld!(tmp.child("*.txt"))
+        .success()
+        .stdout(is_empty())
+        .stderr(
+            contains("0 B saved in the deduplication of 0 files").and(contains(format!(
+                "{} already shared by 2 files",
+                pretty_bytes::converter::convert(2.0 * allocated(&foo) as f64)
+            ))),
+        );
+}
+
+#[test]
+fn sparse_files() {
+    use std::io::{Seek, SeekFrom, Write};
+
+    let _cache_dir = setup_cache_dir();
+    // set up the test dir, with a sparse file and a dense copy
+    let tmp = assert_fs::TempDir::new().unwrap();
+    let dense = tmp.child("dense.img");
+    let sparse = tmp.child("sparse.img");
+    let size = 8 * 1024 * 1024;
+    let mut content = vec![0; size];
+    content[size / 2..size / 2 + 4].copy_from_slice(b"data");
+    dense.write_binary(&content).unwrap();
+    let mut file = fs::File::create(sparse.path()).unwrap();
+    file.seek(SeekFrom::Start(size as u64 / 2)).unwrap();
+    file.write_all(b"data").unwrap();
+    file.set_len(size as u64).unwrap();
+    drop(file);
+
+    for digest in ["blake3", "sha256"] {
+        hld!(
+            "--log-level",
+            "trace",
+            "--digest",
+            digest,
+            "--dry-run",
+            tmp.child("*.img")
+        )
+        .success()
+        .stdout(is_empty())
+        .stderr(
+            contains(format!(
+                "trace: using sparse I/O to hash {}",
+                sparse.path().display()
+            ))
+            .and(contains("saved in the deduplication of 1 files")),
+        );
+    }
+
+    // the sparse file is kept
+    hld!(tmp.child("*.img")).success().stdout(is_empty());
+    assert_eq!(inos(&dense), inos(&sparse));
+    assert!(allocated(&dense) < size as u64);
+    assert_eq!(fs::read(dense.path()).unwrap(), content);
+}
+
+#[test]
+fn min_age() {
+    let lorem_ipsum = lipsum(100);
+    let _cache_dir = setup_cache_dir();
+    // set up the test dir
+    let tmp = assert_fs::TempDir::new().unwrap();
+    let foo = tmp.child("foo.txt");
+    let bar = tmp.child("bar.txt");
+    foo.write_str(&lorem_ipsum).unwrap();
+    bar.write_str(&lorem_ipsum).unwrap();
+
+    // the files have just been written
+    hld!(
+        "--log-level",
+        "debug",
+        "--min-age",
+        "1h",
+        tmp.child("*.txt")
+    )
+    .success()
+    .stdout(is_empty())
+    .stderr(
+        contains("0 B saved in the deduplication of 0 files").and(contains(format!(
+            "debug: skipping the recently modified file {}",
+            foo.path().display()
+        ))),
+    );
+    assert_ne!(inos(&foo), inos(&bar));
+
+    hld!("--min-age", "0s", tmp.child("*.txt"))
+        .success()
+        .stdout(is_empty())
+        .stderr(contains("saved in the deduplication of 1 files"));
+    assert_eq!(inos(&foo), inos(&bar));
+
+    hld!("--min-age", "1y", tmp.child("*.txt"))
+        .code(2)
+        .stderr(contains("invalid value '1y'"));
+    hld!("--min-age", "18446744073709551615d", tmp.child("*.txt"))
+        .code(2)
+        .stderr(contains("invalid duration: 18446744073709551615d"));
+}
+
+#[test]
+fn locked_cache() {
+    let lorem_ipsum = lipsum(100);
+    let cache_dir = setup_cache_dir();
+    // set up the test dir
+    let tmp = assert_fs::TempDir::new().unwrap();
+    let foo = tmp.child("foo.txt");
+    let bar = tmp.child("bar.txt");
+    foo.write_str(&lorem_ipsum).unwrap();
+    bar.write_str(&lorem_ipsum).unwrap();
+
+    // a timeout too large for the clock never expires
+    hld!(
+        "--lock-timeout",
+        "18446744073709551615",
+        "--dry-run",
+        "--cache",
+        tmp.child("*.txt")
+    )
+    .success()
+    .stderr(contains("saved in the deduplication of 1 files"));
+
+    // another process holds the cache lock
+    let lock_file = fs::File::create(cache_dir.child("digests.lock").path()).unwrap();
+    fs2::FileExt::lock_exclusive(&lock_file).unwrap();
+    let holder = format!("by the process {}", std::process::id());
+
+    hld!("--lock-timeout", "1", "--cache", tmp.child("*.txt"))
+        .code(6)
+        .stdout(is_empty())
+        .stderr(contains(format!(
+            "error: {}: timeout while waiting for the cache lock held {holder}",
+            cache_dir.child("digests.lock").path().display()
+        )));
+    assert_ne!(inos(&foo), inos(&bar));
+
+    hld!("--no-wait", "--cache", tmp.child("*.txt"))
+        .success()
+        .stdout(is_empty())
+        .stderr(
+            contains(format!("the cache is locked {holder} - skipping it"))
+                .and(contains("saved in the deduplication of 1 files")),
+        );
+    assert_eq!(inos(&foo), inos(&bar));
+    cache_dir.child("digests").assert(missing());
+}
+
+#[test]
+fn profiles() {
+    let lorem_ipsum = lipsum(100);
+    let _cache_dir = setup_cache_dir();
+    // set up the test dir
+    let tmp = assert_fs::TempDir::new().unwrap();
+    let foo = tmp.child("foo.txt");
+    let bar = tmp.child("bar.txt");
+    let baz = tmp.child("baz.txt");
+    let other_foo = tmp.child("other/foo.txt");
+    let other_bar = tmp.child("other/bar.txt");
+    for file in [&foo, &bar, &baz, &other_foo, &other_bar] {
+        file.write_str(&lorem_ipsum).unwrap();
+    }
+    let config = tmp.child("config.toml");
+    config
+        .write_str(&format!(
+            "[profile.test]\nfiles = [\"{}\"]\nexcludes = [\"*/baz.txt\"]\nstrategy = [\"hardlink\"]\nparallel = 2\n",
+            tmp.child("*.txt").path().display()
+        ))
+        .unwrap();
+
+    // the command line overrides the files of the profile
+    hld!(
+        "--config",
+        config,
+        "--profile",
+        "test",
+        tmp.child("other/*.txt")
+    )
+    .success()
+    .stderr(contains("saved in the deduplication of 1 files"));
+    assert_eq!(inos(&other_foo), inos(&other_bar));
+    assert_ne!(inos(&foo), inos(&bar));
+
+    hld!("--config", config, "--profile", "test", "-l", "debug")
+        .success()
+        .stderr(
+            contains("debug: using 2 threads at most")
+                .and(contains("saved in the deduplication of 1 files")),
+        );
+    assert_eq!(inos(&foo), inos(&bar));
+    assert_ne!(inos(&foo), inos(&baz));
+
+    hld!("--config", config, "--profile", "nope")
+        .code(2)
+        .stderr(contains(format!(
+            "error: {}: unknown profile nope",
+            config.path().display()
+        )));
+
+    config.write_str("[profile.test]\nfile = []\n").unwrap();
+    hld!("--config", config, "--profile", "test")
+        .code(2)
+        .stderr(contains("unknown field `file`"));
+
+    // the completion doesn't depend on the configuration file
+    hld!(
+        "--config",
+        config,
+        "--profile",
+        "test",
+        "--completion",
+        "bash"
+    )
+    .success()
+    .stdout(contains("_hld"));
+}
@@ -43,6 +43,16 @@ fn bad_strategy() {
         ));
 }
 
+#[test]
+fn no_io_threads_per_device() {
+    hld!("--io-threads-per-device", "0")
+        .code(2)
+        .stdout(is_empty())
+        .stderr(contains(
+            r"invalid value '0' for '--io-threads-per-device <N>'",
+        ));
+}
+
 #[test]
 fn log_level_error() {
     let _cache_dir = setup_cache_dir();